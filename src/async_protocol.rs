@@ -0,0 +1,202 @@
+// Async variant of the core protocol operations, mirroring `protocol`.
+//
+// Any `futures-io` reader/writer can be used as a transport, e.g. a port from
+// `tokio-serial` wrapped via `tokio-util`'s `compat`, or an `async-io` `Async`.
+use std::future::{poll_fn, Future};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Poll};
+use std::time::Duration;
+
+use async_io::Timer;
+use futures_io::{AsyncRead, AsyncWrite};
+use tracing::{debug, error, info};
+use zerocopy::FromBytes;
+
+use crate::protocol::{
+    chunk_size, flash_params, response_timeout, BootInfo, Command, CommandPacket, ProtocolError,
+    Result, RomError, FL, HALF_SEC, HANDSHAKE_SCAN_WINDOW, MAGIC, OK, PD, RETRIES,
+};
+
+pub trait Transport: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Transport for T {}
+
+// Fill `buf` completely, or time out after `timeout` like the sync port would.
+async fn read_exact<T: Transport>(port: &mut T, buf: &mut [u8], timeout: Duration) -> Result<()> {
+    let mut timer = Timer::after(timeout);
+    let mut filled = 0;
+    poll_fn(|cx| {
+        while filled < buf.len() {
+            match Pin::new(&mut *port).poll_read(cx, &mut buf[filled..]) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                Poll::Ready(Ok(n)) => filled += n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => {
+                    ready!(Pin::new(&mut timer).poll(cx));
+                    return Poll::Ready(Err(io::ErrorKind::TimedOut.into()));
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    })
+    .await
+    .map_err(ProtocolError::from)
+}
+
+async fn write_all<T: Transport>(port: &mut T, data: &[u8]) -> Result<()> {
+    let mut written = 0;
+    poll_fn(|cx| {
+        while written < data.len() {
+            match ready!(Pin::new(&mut *port).poll_write(cx, &data[written..])) {
+                Ok(0) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Ok(n) => written += n,
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+        Pin::new(&mut *port).poll_flush(cx)
+    })
+    .await
+    .map_err(ProtocolError::from)
+}
+
+// Read the status after sending a command.
+async fn get_ok<T: Transport>(port: &mut T) -> Result<()> {
+    debug!("Check for command OK");
+    let timeout = response_timeout();
+    let mut stat = [0u8; 2];
+    read_exact(port, &mut stat, timeout).await?;
+    while &stat == PD {
+        debug!("Command pending");
+        read_exact(port, &mut stat, timeout).await?;
+    }
+    if &stat == FL {
+        error!("Command failed");
+        let mut code = [0u8; 2];
+        read_exact(port, &mut code, timeout).await?;
+        let e = RomError::from_code(u16::from_le_bytes(code));
+        return Err(ProtocolError::CommandFailed(e));
+    }
+    if &stat != OK {
+        return Err(ProtocolError::UnexpectedStatus(stat));
+    }
+    debug!("Command OK");
+    Ok(())
+}
+
+// Look for the handshake OK within the first bytes, like `protocol` does.
+async fn scan_for_ok<T: Transport>(port: &mut T) -> Result<()> {
+    let mut last = [0u8; 2];
+    for n in 1..=HANDSHAKE_SCAN_WINDOW {
+        let mut b = [0u8; 1];
+        read_exact(port, &mut b, HALF_SEC).await?;
+        last = [last[1], b[0]];
+        if n >= 2 && &last == OK {
+            return Ok(());
+        }
+    }
+    Err(ProtocolError::UnexpectedStatus(last))
+}
+
+async fn send_cmd<T: Transport>(port: &mut T, command: Command, data: &[u8]) -> Result<()> {
+    let cmd = CommandPacket::new(command, data).to_slice();
+    debug!("Command: {cmd:02x?}, data: {data:02x?}");
+    write_all(port, &cmd).await?;
+    write_all(port, data).await
+}
+
+pub async fn get_response<T: Transport>(port: &mut T) -> Result<Vec<u8>> {
+    let timeout = response_timeout();
+    // First we get the size of the response.
+    let mut size = [0u8; 2];
+    read_exact(port, &mut size, timeout).await?;
+    let size = u16::from_le_bytes(size) as usize;
+
+    debug!("Read {size} bytes...");
+    let mut resp = vec![0u8; size];
+    read_exact(port, &mut resp, timeout).await?;
+    Ok(resp)
+}
+
+pub(crate) async fn send<T: Transport>(port: &mut T, command: Command, data: &[u8]) -> Result<()> {
+    send_cmd(port, command, data).await?;
+    get_ok(port).await
+}
+
+pub(crate) async fn send_and_retrieve<T: Transport>(
+    port: &mut T,
+    command: Command,
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    send(port, command, data).await?;
+    get_response(port).await
+}
+
+pub async fn handshake<T: Transport>(port: &mut T) -> Result<()> {
+    debug!("Handshake");
+    let mut last = ProtocolError::Timeout;
+    for r in 0..RETRIES {
+        let attempt = async {
+            write_all(port, &[b'U'; 32]).await?;
+            // Give the auto baud rate detection + adjustment some time.
+            Timer::after(Duration::from_millis(100)).await;
+            write_all(port, &MAGIC).await?;
+            scan_for_ok(port).await
+        };
+        match attempt.await {
+            Ok(()) => {
+                debug!("Status okay, now send command");
+                return Ok(());
+            }
+            Err(e) => {
+                error!("{e}, retry...");
+                Timer::after(Duration::from_millis(r * 200)).await;
+                last = e;
+            }
+        }
+    }
+    error!("Tried handshake {RETRIES} times, to no avail. :(");
+    Err(last)
+}
+
+pub async fn get_boot_info<T: Transport>(port: &mut T) -> Result<BootInfo> {
+    debug!("Get boot info");
+    let res = send_and_retrieve(port, Command::GetBootInfo, &[]).await?;
+    debug!("{res:02x?}");
+    BootInfo::read_from_bytes(&res).map_err(|_| {
+        ProtocolError::InvalidResponse(format!("Unexpected boot info size: {}", res.len()))
+    })
+}
+
+pub async fn get_flash_id<T: Transport>(port: &mut T) -> Result<[u8; 3]> {
+    let bi = get_boot_info(port).await?;
+    send(port, Command::FlashSetParam, &flash_params(&bi)).await?;
+
+    info!("Get JEDEC flash manufacturer/device ID");
+    let res = send_and_retrieve(port, Command::FlashReadJedecId, &[]).await?;
+    match res.get(0..3) {
+        Some(&[m, t, c]) => Ok([m, t, c]),
+        _ => Err(ProtocolError::InvalidResponse(format!(
+            "Unexpected JEDEC ID response: {res:02x?}"
+        ))),
+    }
+}
+
+/// Read `size` bytes from flash at `offset`. Expects the flash to be set up,
+/// e.g. via `get_flash_id`.
+pub async fn read_flash<T: Transport>(port: &mut T, offset: u32, size: u32) -> Result<Vec<u8>> {
+    let chunk = chunk_size();
+    let mut res = Vec::<u8>::with_capacity(size as usize);
+    for a in (offset..offset + size).step_by(chunk as usize) {
+        let l = chunk.min(offset + size - a);
+        debug!("Now reading {l} bytes from {a:08x}");
+        let d = [a.to_le_bytes(), l.to_le_bytes()].concat();
+        res.extend_from_slice(&send_and_retrieve(port, Command::FlashRead, &d).await?);
+    }
+    Ok(res)
+}
+
+pub async fn reset<T: Transport>(port: &mut T) -> Result<()> {
+    debug!("Reset");
+    send(port, Command::Reset, &[]).await
+}
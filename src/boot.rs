@@ -4,8 +4,8 @@
 use std::fmt::Display;
 
 use bitfield_struct::bitfield;
-use log::{debug, info};
 use sha2::Digest;
+use tracing::{info, warn};
 use zerocopy::{FromBytes, IntoBytes};
 use zerocopy_derive::{FromBytes, Immutable, IntoBytes};
 
@@ -15,12 +15,36 @@ pub const D0_LOAD_ADDR: u32 = crate::mem_map::D0_RAM_BASE + 0x7_0000;
 // TODO: try this out; we may not be able to run from here
 pub const LP_LOAD_ADDR: u32 = crate::mem_map::OCRAM_BASE + 0x8000;
 
-const BOOT_MAGIC: &[u8; 4] = b"BFNP";
+/// Where each core's segment is loaded
+#[derive(Clone, Copy, Debug)]
+pub struct LoadAddresses {
+    pub m0: u32,
+    pub d0: u32,
+    pub lp: u32,
+}
+
+impl Default for LoadAddresses {
+    fn default() -> Self {
+        Self {
+            m0: M0_LOAD_ADDR,
+            d0: D0_LOAD_ADDR,
+            lp: LP_LOAD_ADDR,
+        }
+    }
+}
+
+pub const BOOT_MAGIC: &[u8; 4] = b"BFNP";
 const FLASH_CONFIG_MAGIC: &[u8; 4] = b"FCFG";
 const CLOCK_CONFIG_MAGIC: &[u8; 4] = b"PCFG";
 
-const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+pub const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+// The mask ROM skips the CRC check when it finds this value
+const CRC_IGNORE: u32 = 0xdead_beef;
 
+// NOTE: The on-flash structs are `repr(C, packed)`. Copy fields to locals
+// before formatting them or calling methods on them. References to unaligned
+// packed fields are rejected by rustc (E0793); tests/packed_display.rs formats
+// each of them, e.g. for Miri.
 #[derive(FromBytes, Immutable, IntoBytes, Clone, Copy, Debug)]
 #[repr(C, packed)]
 struct FlashConfig {
@@ -228,6 +252,213 @@ impl FlashConfig {
     }
 }
 
+const FLASH_CONFIG_SIZE: usize = size_of::<FlashConfig>();
+
+// Flash parts with a W25Q compatible command set, keyed by JEDEC manufacturer
+// and memory type. All of them work with the vendor's default flash config.
+const KNOWN_FLASH: &[(u8, u8, &str)] = &[
+    (0xef, 0x40, "Winbond W25Q"),
+    (0xef, 0x60, "Winbond W25Q (1.8V)"),
+    (0xef, 0x70, "Winbond W25Q-JV_IM"),
+    (0xc8, 0x40, "GigaDevice GD25Q"),
+    (0xc8, 0x60, "GigaDevice GD25LQ"),
+];
+
+/// Name of a flash part for which `FlashConfig::for_jedec` has parameters
+pub fn known_flash(id: [u8; 3]) -> Option<&'static str> {
+    let k = KNOWN_FLASH
+        .iter()
+        .find(|(m, t, _)| (*m, *t) == (id[0], id[1]));
+    k.map(|(_, _, n)| *n)
+}
+
+impl FlashConfig {
+    /// Parameters for a known flash part, from the vendor's default
+    /// `bootheader_cfg.conf` for BL808 with the manufacturer ID filled in
+    pub fn for_jedec(id: [u8; 3]) -> Option<Self> {
+        known_flash(id)?;
+        let mut c = Self {
+            magic: u32::from_le_bytes(*FLASH_CONFIG_MAGIC),
+
+            io_mode: 0x11,
+            continuous_read_support: 1,
+            sfctrl_clock_delay: 1,
+            sfctrl_clock_invert: 0x01,
+
+            reset_en_command: 0x66,
+            reset_command: 0x99,
+            exit_continuousread_command: 0xff,
+            exit_continuousread_command_size: 3,
+
+            jedec_id_command: 0x9f,
+            jedec_id_command_dummy_clock: 0,
+            enter_32bits_addr_command: 0xb7,
+            exit_32bits_addr_clock: 0xe9,
+
+            sector_size: 4,
+            mfg_id: id[0],
+            page_size: 256,
+
+            chip_erase_command: 0xc7,
+            sector_erase_command: 0x20,
+            blk32k_erase_command: 0x52,
+            blk64k_erase_command: 0xd8,
+
+            write_enable_command: 0x06,
+            page_prog_command: 0x02,
+            qpage_prog_command: 0x32,
+            qual_page_prog_addr_mode: 0,
+
+            fast_read_command: 0x0b,
+            fast_read_dummy_clock: 1,
+            qpi_fast_read_command: 0x0b,
+            qpi_fast_read_dummy_clock: 1,
+
+            fast_read_do_command: 0x3b,
+            fast_read_do_dummy_clock: 1,
+            fast_read_dio_command: 0xbb,
+            fast_read_dio_dummy_clock: 0,
+
+            fast_read_qo_command: 0x6b,
+            fast_read_qo_dummy_clock: 1,
+            fast_read_qio_command: 0xeb,
+            fast_read_qio_dummy_clock: 2,
+
+            qpi_fast_read_qio_command: 0xeb,
+            qpi_fast_read_qio_dummy_clock: 2,
+            qpi_page_prog_command: 0x02,
+            write_vreg_enable_command: 0x50,
+
+            wel_reg_index: 0,
+            qe_reg_index: 1,
+            busy_reg_index: 0,
+            wel_bit_pos: 1,
+
+            qe_bit_pos: 1,
+            busy_bit_pos: 0,
+            wel_reg_write_len: 2,
+            wel_reg_read_len: 1,
+
+            qe_reg_write_len: 2,
+            qe_reg_read_len: 1,
+            release_power_down: 0xab,
+            busy_reg_read_len: 1,
+
+            reg_read_command0: 0x05,
+            reg_read_command1: 0x35,
+            _reserved0: 0,
+
+            reg_write_command0: 0x01,
+            reg_write_command1: 0x01,
+            _reserved1: 0,
+
+            enter_qpi_command: 0x38,
+            exit_qpi_command: 0xff,
+            continuous_read_code: 0xa0,
+            continuous_read_exit_code: 0xff,
+
+            burst_wrap_command: 0x77,
+            burst_wrap_dummy_clock: 0x03,
+            burst_wrap_data_mode: 2,
+            burst_wrap_code: 0x40,
+
+            de_burst_wrap_command: 0x77,
+            de_burst_wrap_command_dummy_clock: 0x03,
+            de_burst_wrap_code_mode: 2,
+            de_burst_wrap_code: 0xf0,
+
+            // in milliseconds
+            sector_erase_time: 300,
+            blk32k_erase_time: 1200,
+
+            blk64k_erase_time: 1200,
+            page_prog_time: 5,
+
+            chip_erase_time: 33000,
+            power_down_delay: 20,
+            qe_data: 0,
+
+            crc32: 0,
+        };
+        c.crc32 = c.crc();
+        Some(c)
+    }
+}
+
+/// Erase commands to override in a flash config. Common parts use 0x20 for
+/// 4K sectors, 0x52 / 0xd8 for 32K / 64K blocks and 0xc7 for the whole chip.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EraseCommands {
+    pub sector: Option<u8>,
+    pub blk32k: Option<u8>,
+    pub blk64k: Option<u8>,
+    pub chip: Option<u8>,
+}
+
+impl EraseCommands {
+    pub fn is_empty(&self) -> bool {
+        [self.sector, self.blk32k, self.blk64k, self.chip]
+            .iter()
+            .all(Option::is_none)
+    }
+}
+
+impl FlashConfig {
+    fn has_magic(&self) -> bool {
+        &self.magic.to_le_bytes() == FLASH_CONFIG_MAGIC
+    }
+
+    // The CRC covers everything between the magic and itself.
+    fn crc(&self) -> u32 {
+        CRC32.checksum(&self.as_bytes()[4..FLASH_CONFIG_SIZE - 4])
+    }
+
+    fn with_erase_commands(mut self, c: &EraseCommands) -> Result<Self, String> {
+        let cmds = [c.sector, c.blk32k, c.blk64k, c.chip];
+        if cmds.contains(&Some(0)) {
+            return Err("Erase commands must not be 0".to_string());
+        }
+        self.sector_erase_command = c.sector.unwrap_or(self.sector_erase_command);
+        self.blk32k_erase_command = c.blk32k.unwrap_or(self.blk32k_erase_command);
+        self.blk64k_erase_command = c.blk64k.unwrap_or(self.blk64k_erase_command);
+        self.chip_erase_command = c.chip.unwrap_or(self.chip_erase_command);
+        self.crc32 = self.crc();
+        Ok(self)
+    }
+}
+
+/// Take the flash config from `base`, either a boot image or a raw config
+/// starting with the FCFG magic, and override its erase commands. Returns the
+/// config without magic and CRC, as `FlashSetParam` takes it.
+pub fn flash_config_with_erase(base: &[u8], cmds: &EraseCommands) -> Result<Vec<u8>, String> {
+    let fc = if base.starts_with(BOOT_MAGIC) {
+        BootHeader::parse(base)
+            .map_err(|e| e.to_string())?
+            .flash_config
+    } else {
+        match FlashConfig::read_from_prefix(base) {
+            Ok((fc, _)) => fc,
+            Err(_) => return Err("Flash config is truncated".to_string()),
+        }
+    };
+    if !fc.has_magic() {
+        return Err("No flash config found (FCFG magic missing)".to_string());
+    }
+    let crc = fc.crc32;
+    if crc != fc.crc() {
+        warn!("Flash config CRC mismatch, using it anyway");
+    }
+    let fc = fc.with_erase_commands(cmds)?;
+    let (s, b32, b64, c) = (
+        fc.sector_erase_command,
+        fc.blk32k_erase_command,
+        fc.blk64k_erase_command,
+        fc.chip_erase_command,
+    );
+    info!("Erase commands: sector {s:02x}, 32K {b32:02x}, 64K {b64:02x}, chip {c:02x}");
+    Ok(fc.as_bytes()[4..FLASH_CONFIG_SIZE - 4].to_vec())
+}
+
 impl Display for FlashConfig {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{self:#02x?}")
@@ -306,6 +537,46 @@ impl ClockConfig {
         c.crc32 = CRC32.checksum(bytes);
         c
     }
+
+    /// 40 MHz crystal with all PLLs powered up, from the vendor's default
+    /// `bootheader_cfg.conf` for BL808
+    pub fn bl808_default() -> Self {
+        let mut c = Self {
+            magic: u32::from_le_bytes(*CLOCK_CONFIG_MAGIC),
+
+            // 0: none, 1: 24M, 2: 32M, 3: 38.4M, 4: 40M, 5: 26M, 6: RC32M
+            xtal_type: 4,
+            mcu_clock: 4,
+            mcu_clock_divider: 0,
+            mcu_bclock_divider: 0,
+
+            mcu_pbclock_divider: 3,
+            lp_divider: 1,
+            dsp_clock: 3,
+            dsp_clock_divider: 0,
+
+            dsp_bclock_divider: 1,
+            dsp_pbclock: 2,
+            dsp_pbclock_divider: 0,
+            emi_clock: 2,
+
+            emi_clock_divider: 1,
+            flash_clock_type: 1,
+            flash_clock_divider: 0,
+            wifi_pll_pu: 1,
+
+            au_pll_pu: 1,
+            cpu_pll_pu: 1,
+            mipi_pll_pu: 1,
+            uhs_pll_pu: 1,
+
+            crc32: 0,
+        };
+        // Like for the flash config, the CRC covers what is between the magic
+        // and itself.
+        c.crc32 = CRC32.checksum(&c.as_bytes()[4..CLOCK_CONFIG_SIZE - 4]);
+        c
+    }
 }
 
 impl Display for ClockConfig {
@@ -367,7 +638,7 @@ impl BootConfig {
             hash.update(s.data);
         }
         let sha256: [u8; 32] = hash.finalize().into();
-        let mut config = BootConfigBits::new()
+        let config = BootConfigBits::new()
             .with_no_segment(true)
             // power on D0 (C096) aka MM aka MultiMedia core
             .with_power_on_mm(true)
@@ -455,12 +726,13 @@ impl CpuConfig {
         }
     }
 
-    pub fn with_entry(boot_entry: u32) -> Self {
-        let mut cpu_enable_and_cache = CpuEnableAndCache::new().with_config_enable(1);
+    /// The image offset is where the core's segment starts in the image.
+    pub fn with_entry(boot_entry: u32, image_offset: u32) -> Self {
+        let cpu_enable_and_cache = CpuEnableAndCache::new().with_config_enable(1);
         Self {
             cpu_enable_and_cache,
             cache_range: CacheRange { start: 0, end: 0 },
-            image_offset: 0,
+            image_offset,
             boot_entry,
             msp_val: 0,
         }
@@ -508,7 +780,48 @@ pub struct BootHeader {
     crc32: u32,
 }
 
-const BOOT_HEADER_SIZE: usize = std::mem::size_of::<BootHeader>();
+pub const BOOT_HEADER_SIZE: usize = std::mem::size_of::<BootHeader>();
+
+// Offsets in the BL808 boot header as the vendor tool lays it out, so that a
+// reordered or resized field fails the build instead of bricking a board.
+const _: () = assert!(BOOT_HEADER_SIZE == 0x160);
+const _: () = assert!(std::mem::offset_of!(BootHeader, flash_config) == 0x08);
+const _: () = assert!(std::mem::offset_of!(BootHeader, clock_config) == 0x64);
+const _: () = assert!(std::mem::offset_of!(BootHeader, boot_config) == 0x80);
+const _: () = assert!(std::mem::offset_of!(BootHeader, m0_config) == 0xb0);
+const _: () = assert!(std::mem::offset_of!(BootHeader, d0_config) == 0xc8);
+const _: () = assert!(std::mem::offset_of!(BootHeader, lp_config) == 0xe0);
+const _: () = assert!(std::mem::offset_of!(BootHeader, boot2_partition_table_0) == 0xf8);
+const _: () = assert!(std::mem::offset_of!(BootHeader, patch_config) == 0x108);
+const _: () = assert!(std::mem::offset_of!(BootHeader, crc32) == 0x15c);
+
+// Header revisions this tool knows the layout of
+const KNOWN_REVISIONS: &[u32] = &[1];
+
+#[derive(Debug)]
+pub enum ParseError {
+    TooShort { len: usize },
+    BadMagic { found: [u8; 4] },
+    UnknownRevision { found: u32 },
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooShort { len } => write!(
+                f,
+                "not a boot image (size {len} is less than the {BOOT_HEADER_SIZE} byte header)"
+            ),
+            Self::BadMagic { found } => {
+                let s = String::from_utf8_lossy(found);
+                write!(f, "not a boot image (magic was {found:02x?} {s:?})")
+            }
+            Self::UnknownRevision { found } => {
+                write!(f, "unsupported boot header revision {found}")
+            }
+        }
+    }
+}
 
 impl BootHeader {
     pub fn new(m0_seg: Option<Segment>, d0_seg: Option<Segment>, lp_seg: Option<Segment>) -> Self {
@@ -522,27 +835,28 @@ impl BootHeader {
         if let Some(s) = lp_seg {
             segments.push(s);
         }
+        // Segments follow each other from SEGMENTS_OFFSET in this order.
+        let mut offset = SEGMENTS_OFFSET as u32;
+        let mut cpu_config = |seg: Option<Segment>| match seg {
+            Some(s) => {
+                let c = CpuConfig::with_entry(s.header.address, offset);
+                offset += (SEGMENT_HEADER_SIZE + s.data.len()) as u32;
+                c
+            }
+            None => CpuConfig::new(),
+        };
+        let m0_config = cpu_config(m0_seg);
+        let d0_config = cpu_config(d0_seg);
+        let lp_config = cpu_config(lp_seg);
         let mut h = Self {
             magic: *BOOT_MAGIC,
             revision: 1,
             flash_config: FlashConfig::new(),
             clock_config: ClockConfig::new(),
             boot_config: BootConfig::new(&segments),
-            m0_config: if let Some(s) = m0_seg {
-                CpuConfig::with_entry(s.header.address)
-            } else {
-                CpuConfig::new()
-            },
-            d0_config: if let Some(s) = d0_seg {
-                CpuConfig::with_entry(s.header.address)
-            } else {
-                CpuConfig::new()
-            },
-            lp_config: if let Some(s) = lp_seg {
-                CpuConfig::with_entry(s.header.address)
-            } else {
-                CpuConfig::new()
-            },
+            m0_config,
+            d0_config,
+            lp_config,
             boot2_partition_table_0: 0,
             boot2_partition_table_1: 0,
             flash_config_table_addr: 0,
@@ -556,6 +870,164 @@ impl BootHeader {
         h.crc32 = CRC32.checksum(bytes);
         h
     }
+
+    /// Decode a header, checking the magic and revision first
+    pub fn parse(data: &[u8]) -> Result<Self, ParseError> {
+        let Ok((h, _)) = Self::read_from_prefix(data) else {
+            return Err(ParseError::TooShort { len: data.len() });
+        };
+        if !h.has_magic() {
+            return Err(ParseError::BadMagic { found: h.magic });
+        }
+        let revision = h.revision;
+        if !KNOWN_REVISIONS.contains(&revision) {
+            return Err(ParseError::UnknownRevision { found: revision });
+        }
+        Ok(h)
+    }
+
+    fn update_crc(&mut self) {
+        let cfg = self.boot_config.config;
+        if cfg.crc_ignore() {
+            self.crc32 = CRC_IGNORE;
+            return;
+        }
+        let bytes = &self.as_bytes()[..BOOT_HEADER_SIZE - 4];
+        self.crc32 = CRC32.checksum(bytes);
+    }
+
+    /// Set up the clocks as for booting from flash, while a RAM run keeps the
+    /// ROM's own setup.
+    pub fn with_default_clock_config(mut self) -> Self {
+        self.clock_config = ClockConfig::bl808_default();
+        self.update_crc();
+        self
+    }
+
+    /// Use the flash parameters for a known part, see `FlashConfig::for_jedec`.
+    pub fn with_flash_config(mut self, id: [u8; 3]) -> Result<Self, String> {
+        let Some(fc) = FlashConfig::for_jedec(id) else {
+            return Err(format!("No flash config known for JEDEC ID {id:02x?}"));
+        };
+        self.flash_config = fc;
+        self.update_crc();
+        Ok(self)
+    }
+
+    /// Have the mask ROM skip the header CRC and/or segments hash checks.
+    pub fn with_ignore(mut self, ignore: IgnoreChecks) -> Self {
+        let cfg = self.boot_config.config;
+        self.boot_config.config = cfg
+            .with_crc_ignore(ignore.crc)
+            .with_hash_ignore(ignore.hash);
+        self.update_crc();
+        if ignore.crc || ignore.hash {
+            warn!("Image checks disabled ({ignore}), the image is unverified");
+        }
+        self
+    }
+
+    /// Set the encrypted region of the segment data and whether it is locked.
+    /// `image_len` is the size of all segment headers and data.
+    pub fn with_aes_region(mut self, region: AesRegion, image_len: usize) -> Result<Self, String> {
+        let AesRegion {
+            length,
+            lock,
+            key_selection,
+        } = region;
+        if key_selection > MAX_KEY_SELECTION {
+            return Err(format!(
+                "Key selection {key_selection} exceeds {MAX_KEY_SELECTION}"
+            ));
+        }
+        if !(length as usize).is_multiple_of(AES_BLOCK_SIZE) {
+            return Err(format!(
+                "AES region length {length:08x} is not {AES_BLOCK_SIZE}-byte aligned"
+            ));
+        }
+        if length as usize > image_len {
+            return Err(format!(
+                "AES region length {length:08x} exceeds the image data ({image_len:08x})"
+            ));
+        }
+        self.boot_config.aes_region_length = length;
+        let cfg = self.boot_config.config;
+        self.boot_config.config = cfg
+            .with_aes_region_lock(lock)
+            .with_key_selection(key_selection);
+        self.update_crc();
+        Ok(self)
+    }
+
+    /// SHA256 over all segment headers and data, as the mask ROM checks it
+    pub fn segments_sha256(&self) -> [u8; 32] {
+        self.boot_config.sha256
+    }
+
+    /// Number of segments following the header
+    pub fn segment_count(&self) -> Result<usize, String> {
+        let count = self.boot_config.image_length_or_segment_count as usize;
+        if count > MAX_SEGMENTS {
+            return Err(format!("Not a segmented image, segment count {count}"));
+        }
+        Ok(count)
+    }
+
+    /// Each core's config, showing which cores the image starts and where
+    pub fn cpu_configs(&self) -> String {
+        let cores = [
+            ("M0", self.m0_config),
+            ("D0", self.d0_config),
+            ("LP", self.lp_config),
+        ];
+        let cfgs = cores.map(|(n, c)| {
+            let cc = c.cpu_enable_and_cache;
+            let state = match (cc.config_enable() != 0, cc.halt_cpu() != 0) {
+                (false, _) => "not configured",
+                (true, true) => "halted",
+                (true, false) => "started",
+            };
+            format!("{n} config, {state}:\n{c}")
+        });
+        cfgs.join("\n\n")
+    }
+
+    fn enabled_cores(&self) -> Vec<(&'static str, CpuConfig)> {
+        let cores = [
+            ("M0", self.m0_config),
+            ("D0", self.d0_config),
+            ("LP", self.lp_config),
+        ];
+        cores
+            .into_iter()
+            .filter(|(_, c)| {
+                let cc = c.cpu_enable_and_cache;
+                cc.config_enable() != 0
+            })
+            .collect()
+    }
+
+    /// Entry points of the cores enabled in the header
+    pub fn entry_points(&self) -> Vec<(&'static str, u32)> {
+        let cores = self.enabled_cores().into_iter();
+        cores.map(|(n, c)| (n, c.boot_entry)).collect()
+    }
+
+    /// Where the segments of the cores enabled in the header start in the image
+    pub fn image_offsets(&self) -> Vec<(&'static str, u32)> {
+        let cores = self.enabled_cores().into_iter();
+        cores.map(|(n, c)| (n, c.image_offset)).collect()
+    }
+
+    pub fn has_magic(&self) -> bool {
+        &self.magic == BOOT_MAGIC
+    }
+
+    /// Check the CRC32, honoring the "ignore" value
+    pub fn crc_ok(&self) -> bool {
+        let crc = self.crc32;
+        crc == CRC_IGNORE || crc == CRC32.checksum(&self.as_bytes()[..BOOT_HEADER_SIZE - 4])
+    }
 }
 
 impl Display for BootHeader {
@@ -587,7 +1059,7 @@ impl Display for BootHeader {
         let pj = format!("Patch jump:   {pj:08x?}");
 
         let crc = self.crc32;
-        let crcx = if crc == 0xdead_beef { " (ignore)" } else { "" };
+        let crcx = if crc == CRC_IGNORE { " (ignore)" } else { "" };
         let crc = format!("CRC32: {crc:02x?}{crcx}");
 
         let extra = format!("{bpt}\n{fc}\n{pc}\n{pj}\n{crc}");
@@ -599,13 +1071,15 @@ impl Display for BootHeader {
 #[derive(FromBytes, Immutable, IntoBytes, Clone, Copy, Debug)]
 #[repr(C, packed)]
 pub struct SegmentHeader {
-    address: u32,
-    size: u32,
+    pub address: u32,
+    pub size: u32,
     _reserved: u32,
     crc32: u32,
 }
 
-const SEGMENT_HEADER_SIZE: usize = std::mem::size_of::<SegmentHeader>();
+pub const SEGMENT_HEADER_SIZE: usize = std::mem::size_of::<SegmentHeader>();
+
+const _: () = assert!(SEGMENT_HEADER_SIZE == 0x10);
 
 impl SegmentHeader {
     pub fn new(address: u32, size: u32) -> Self {
@@ -620,6 +1094,12 @@ impl SegmentHeader {
         h.crc32 = crc32;
         h
     }
+
+    /// Check the CRC32, which covers only the header, not the data
+    pub fn crc_ok(&self) -> bool {
+        let crc = self.crc32;
+        crc == CRC32.checksum(&self.as_bytes()[..SEGMENT_HEADER_SIZE - 4])
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -638,9 +1118,347 @@ impl<'a> Segment<'a> {
     }
 }
 
-pub fn parse_image(image: &[u8]) {
+/// Check that a segment fits into the RAM region at its load address. Unknown
+/// regions pass, `align_segment` already warns about them.
+pub fn check_segment(s: &Segment) -> Result<(), String> {
+    let (a, l) = (s.header.address, s.data.len() as u32);
+    match crate::mem_map::region(a) {
+        Some(r) if crate::mem_map::contains(a, l).is_none() => {
+            let (n, b) = (r.name, r.base);
+            let o = a - b;
+            let room = crate::mem_map::ram_end(a).unwrap_or(a) - a;
+            Err(format!(
+                "Segment of {l:#x} bytes at {n} {b:#x} + {o:#x} exceeds the {room:#x} bytes of RAM there"
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+// The mask ROM rejects segments that are not word-aligned.
+pub const SEGMENT_ALIGNMENT: usize = 4;
+
+/// Check that a segment's load address is aligned and pad its data with zeros
+/// to the next `SEGMENT_ALIGNMENT` boundary if necessary.
+pub fn align_segment(address: u32, mut data: Vec<u8>) -> Result<Vec<u8>, String> {
+    if !(address as usize).is_multiple_of(SEGMENT_ALIGNMENT) {
+        return Err(format!(
+            "Load address {address:08x} is not {SEGMENT_ALIGNMENT}-byte aligned"
+        ));
+    }
+    if crate::mem_map::region(address).is_none() {
+        warn!("Load address {address:08x} is not in a known RAM region");
+    }
+    let l = data.len();
+    let r = l % SEGMENT_ALIGNMENT;
+    if r != 0 {
+        let pad = SEGMENT_ALIGNMENT - r;
+        warn!("Segment @ {address:08x}: size {l} is not aligned, pad by {pad} bytes");
+        data.resize(l + pad, 0);
+    }
+    Ok(data)
+}
+
+// The AES engine works on 16-byte blocks.
+const AES_BLOCK_SIZE: usize = 16;
+
+/// Encrypted part of the segment data, starting right after the boot header
+#[derive(Clone, Copy, Debug)]
+pub struct AesRegion {
+    pub length: u32,
+    pub lock: bool,
+    /// eFuse key slot selection, shared by all cores' segments
+    pub key_selection: u8,
+}
+
+const MAX_KEY_SELECTION: u8 = 3;
+
+/// Checks for the mask ROM to skip, for bring-up with hand-edited images
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IgnoreChecks {
+    pub crc: bool,
+    pub hash: bool,
+}
+
+impl Display for IgnoreChecks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let c = self.crc;
+        let h = self.hash;
+        write!(f, "ignore CRC: {c}, ignore hash: {h}")
+    }
+}
+
+// Segments follow the boot header at this offset in an image file.
+pub const SEGMENTS_OFFSET: usize = 0x2000;
+// In image files, each segment's data is padded with zeros to whole AES
+// blocks so that an AES region can end on any segment boundary, and the file
+// with 0xff to whole flash sectors, as erased flash reads.
+pub const IMAGE_SEGMENT_ALIGNMENT: usize = AES_BLOCK_SIZE;
+pub const IMAGE_ALIGNMENT: usize = crate::flash::SECTOR_SIZE as usize;
+// Sanity limit, one segment per core is what we build
+const MAX_SEGMENTS: usize = 8;
+
+/// Create an image file: the boot header, padded up to `SEGMENTS_OFFSET`,
+/// followed by each segment's header and data, see `IMAGE_SEGMENT_ALIGNMENT`
+/// for the padding. Pass the JEDEC ID of the target's flash for a flash
+/// config it can boot from.
+pub fn build_image(
+    m0_seg: Option<Segment>,
+    d0_seg: Option<Segment>,
+    lp_seg: Option<Segment>,
+    aes_region: Option<AesRegion>,
+    ignore: IgnoreChecks,
+    flash_id: Option<[u8; 3]>,
+) -> Result<Vec<u8>, String> {
+    let mut r = Vec::new();
+    write_image(&mut r, m0_seg, d0_seg, lp_seg, aes_region, ignore, flash_id)?;
+    Ok(r)
+}
+
+/// Like `build_image`, but write the image to `out` as it goes, e.g. a file.
+pub fn write_image(
+    out: &mut impl std::io::Write,
+    m0_seg: Option<Segment>,
+    d0_seg: Option<Segment>,
+    lp_seg: Option<Segment>,
+    aes_region: Option<AesRegion>,
+    ignore: IgnoreChecks,
+    flash_id: Option<[u8; 3]>,
+) -> Result<(), String> {
+    let pad = |s: Option<Segment>| s.map(|s| (s.header.address, pad_segment_data(&s)));
+    let (m0_data, d0_data, lp_data) = (pad(m0_seg), pad(d0_seg), pad(lp_seg));
+    let (m0_seg, d0_seg, lp_seg) = (seg(&m0_data), seg(&d0_data), seg(&lp_data));
+    for s in [m0_seg, d0_seg, lp_seg].iter().flatten() {
+        check_segment(s)?;
+    }
+    let mut header = BootHeader::new(m0_seg, d0_seg, lp_seg).with_default_clock_config();
+    match flash_id {
+        Some(id) => {
+            header = header.with_flash_config(id)?;
+            info!("Flash config for {}", known_flash(id).unwrap_or_default());
+        }
+        None => warn!("No flash ID given, the image has an empty flash config"),
+    }
+    if let Some(region) = aes_region {
+        let segments = [m0_seg, d0_seg, lp_seg].into_iter().flatten();
+        let len = segments.map(|s| SEGMENT_HEADER_SIZE + s.data.len()).sum();
+        header = header.with_aes_region(region, len)?;
+        warn!("Segment data is not encrypted here, encrypt it before flashing");
+    }
+    let header = header.with_ignore(ignore);
+    let sha = header.segments_sha256();
+    info!("Segments SHA256: {sha:02x?}");
+    let mut write = |d: &[u8]| out.write_all(d).map_err(|e| e.to_string());
+    write(header.as_bytes())?;
+    write(&[0xff; SEGMENTS_OFFSET - BOOT_HEADER_SIZE])?;
+    let mut len = SEGMENTS_OFFSET;
+    for s in [m0_seg, d0_seg, lp_seg].into_iter().flatten() {
+        write(s.header.as_bytes())?;
+        write(s.data)?;
+        len += SEGMENT_HEADER_SIZE + s.data.len();
+    }
+    write(&vec![0xff; len.next_multiple_of(IMAGE_ALIGNMENT) - len])?;
+    Ok(())
+}
+
+fn seg(d: &Option<(u32, Vec<u8>)>) -> Option<Segment<'_>> {
+    d.as_ref().map(|(a, d)| Segment::new(*a, d))
+}
+
+fn pad_segment_data(s: &Segment) -> Vec<u8> {
+    let mut d = s.data.to_vec();
+    let l = d.len().next_multiple_of(IMAGE_SEGMENT_ALIGNMENT);
+    if l != d.len() {
+        let a = s.header.address;
+        info!("Segment @ {a:08x}: pad {} to {l} bytes", d.len());
+        d.resize(l, 0);
+    }
+    d
+}
+
+/// An image as created by `build_image`
+pub struct Image<'a> {
+    pub header: BootHeader,
+    pub segments: Vec<Segment<'a>>,
+}
+
+impl<'a> Image<'a> {
+    pub fn parse(image: &'a [u8]) -> Result<Self, String> {
+        let header = BootHeader::parse(image).map_err(|e| e.to_string())?;
+        let count = header.segment_count()?;
+        let mut segments = Vec::<Segment>::new();
+        let mut o = SEGMENTS_OFFSET;
+        for i in 0..count {
+            let rest = image.get(o..).unwrap_or_default();
+            let Ok((h, rest)) = SegmentHeader::read_from_prefix(rest) else {
+                return Err(format!("Segment {i} header @ {o:08x} is truncated"));
+            };
+            let size = h.size as usize;
+            let Some(data) = rest.get(..size) else {
+                return Err(format!("Segment {i} data @ {o:08x} is truncated"));
+            };
+            segments.push(Segment { header: h, data });
+            o += SEGMENT_HEADER_SIZE + size;
+        }
+        Ok(Self { header, segments })
+    }
+
+    /// Hash over all segment headers and data, to compare with the boot header
+    pub fn segments_sha256(&self) -> [u8; 32] {
+        let mut hash = sha2::Sha256::new();
+        for s in &self.segments {
+            hash.update(s.header.as_bytes());
+            hash.update(s.data);
+        }
+        hash.finalize().into()
+    }
+
+    /// File offsets of the segments
+    pub fn segment_offsets(&self) -> Vec<usize> {
+        let mut o = SEGMENTS_OFFSET;
+        let mut r = Vec::new();
+        for s in &self.segments {
+            r.push(o);
+            o += SEGMENT_HEADER_SIZE + s.data.len();
+        }
+        r
+    }
+
+    /// Where each segment is and whether its header CRC matches
+    pub fn segment_info(&self) -> Vec<SegmentInfo> {
+        let offsets = self.segment_offsets();
+        let info = self.segments.iter().zip(offsets).map(|(s, o)| SegmentInfo {
+            address: s.header.address,
+            size: s.header.size,
+            file_offset: o + SEGMENT_HEADER_SIZE,
+            crc_ok: s.header.crc_ok(),
+        });
+        info.collect()
+    }
+}
+
+/// A segment's place in an image file, for scripts to extract it
+#[derive(Clone, Copy, Debug)]
+pub struct SegmentInfo {
+    /// Load address
+    pub address: u32,
+    pub size: u32,
+    /// Offset of the data in the image, after the segment header
+    pub file_offset: usize,
+    /// Whether the segment header CRC matches
+    pub crc_ok: bool,
+}
+
+/// Pre-flight check before flashing: the header has to be valid and its CRC
+/// and segments SHA256 have to match.
+pub fn check_image(image: &[u8]) -> Result<(), String> {
+    let i = Image::parse(image)?;
+    if !i.header.crc_ok() {
+        return Err("Boot header CRC mismatch".to_string());
+    }
+    let sha = i.segments_sha256();
+    if sha != i.header.segments_sha256() {
+        return Err(format!("Segments SHA256 mismatch, got {sha:02x?}"));
+    }
+    for (n, s) in i.segments.iter().enumerate() {
+        let a = s.header.address;
+        let l = s.header.size;
+        info!("Segment {n}: {l} bytes @ {a:08x}");
+    }
+    for (core, e) in i.header.entry_points() {
+        info!("{core} entry point: {e:08x}");
+    }
+    // Older images from this tool leave the offsets at 0, so only warn.
+    let offsets = i.segment_offsets();
+    for (core, o) in i.header.image_offsets() {
+        if !offsets.contains(&(o as usize)) {
+            warn!("{core} image offset {o:08x} is not where a segment starts");
+        }
+    }
+    Ok(())
+}
+
+/// Check that an image built from `segments` parses back to them, padded as
+/// by `build_image`, to catch layout mistakes before the image reaches a board.
+pub fn check_round_trip(image: &[u8], segments: &[Segment]) -> Result<(), String> {
+    check_image(image)?;
+    let i = Image::parse(image)?;
+    let (got, want) = (i.segments.len(), segments.len());
+    if got != want {
+        return Err(format!("Image has {got} segments, built from {want}"));
+    }
+    for (n, (g, w)) in i.segments.iter().zip(segments).enumerate() {
+        let (ga, gs) = (g.header.address, g.header.size);
+        let wa = w.header.address;
+        let ws = (w.header.size as usize).next_multiple_of(IMAGE_SEGMENT_ALIGNMENT) as u32;
+        if (ga, gs) != (wa, ws) || !g.data.starts_with(w.data) {
+            return Err(format!(
+                "Segment {n} reads back as {gs} bytes @ {ga:08x}, built as {ws} bytes @ {wa:08x}"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The header with all sub-configs decoded, the segment headers and where
+/// the segments are (`SegmentInfo`), as JSON
+pub fn image_json(image: &[u8]) -> Result<String, String> {
+    let i = Image::parse(image)?;
+    let h = crate::json::from_debug(&i.header);
+    let segments = i
+        .segments
+        .iter()
+        .map(|s| crate::json::from_debug(&s.header));
+    let segments = segments.collect::<Vec<_>>().join(",");
+    let map = i.segment_info();
+    let map = map.iter().map(crate::json::from_debug);
+    let map = map.collect::<Vec<_>>().join(",");
+    Ok(format!(
+        "{{\"header\":{h},\"segments\":[{segments}],\"map\":[{map}]}}"
+    ))
+}
+
+/// Print an image's header and segments and return where the segments are,
+/// empty if they cannot be parsed. CRC mismatches are warnings, or errors
+/// with `strict`.
+pub fn parse_image(image: &[u8], strict: bool) -> Result<Vec<SegmentInfo>, String> {
     info!("Image size: {}K", image.len() / 1024);
-    if let Ok((bh, _)) = BootHeader::read_from_prefix(image) {
-        info!("{bh}");
+    let bh = BootHeader::parse(image).map_err(|e| e.to_string())?;
+    info!("{bh}");
+    let mut mismatches = Vec::<String>::new();
+    let crc = bh.crc32;
+    if crc == CRC_IGNORE {
+        info!("Boot header CRC: ignored");
+    } else if !bh.crc_ok() {
+        mismatches.push(format!("Boot header CRC mismatch, stored {crc:08x}"));
+    }
+    let mut info = Vec::new();
+    match Image::parse(image) {
+        Ok(i) => {
+            let pass = |ok| if ok { "PASS" } else { "FAIL" };
+            info = i.segment_info();
+            for (n, s) in info.iter().enumerate() {
+                let a = s.address;
+                let l = s.size;
+                let o = s.file_offset - SEGMENT_HEADER_SIZE;
+                let crc = pass(s.crc_ok);
+                info!("Segment {n}: {l} bytes @ {a:08x}, image offset {o:08x}, header CRC {crc}");
+                if !s.crc_ok {
+                    mismatches.push(format!("Segment {n} header CRC mismatch"));
+                }
+            }
+            // There is no CRC over the data, only this hash over all segments.
+            let sha = i.segments_sha256();
+            let ok = pass(sha == i.header.segments_sha256());
+            info!("Segments SHA256 {ok}: {sha:02x?}");
+        }
+        Err(e) => warn!("{e}"),
+    }
+    if strict && !mismatches.is_empty() {
+        return Err(mismatches.join(", "));
+    }
+    for m in mismatches {
+        warn!("{m}");
     }
+    Ok(info)
 }
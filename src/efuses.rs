@@ -1,5 +1,6 @@
 #![allow(non_camel_case_types)]
 use std::fmt::{Debug, Display, Formatter};
+use std::ops::Range;
 
 // NOTE: bitfields/bitflags/... are *not* trivial. See also:
 // https://hecatia-elegua.github.io/blog/no-more-bit-fiddling/#how-bilge-came-to-be
@@ -40,7 +41,6 @@ impl Display for SecureBootEnable {
             Self::X1 => "Mode 1",
             Self::X2 => "Mode 2",
             Self::X3 => "Mode 3",
-            _ => unreachable!(),
         };
         write!(f, "{descr}")
     }
@@ -77,7 +77,6 @@ impl Display for AesMode {
             Self::Aes128 => "AES 128",
             Self::Aes192 => "AES 192",
             Self::Aes256 => "AES 256",
-            _ => unreachable!(),
         };
         write!(f, "{descr}")
     }
@@ -152,7 +151,7 @@ impl Display for Flash {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u64)]
 pub enum Psram {
     No = 0,
@@ -165,10 +164,6 @@ pub enum Psram {
 }
 
 impl Psram {
-    const fn into_bits(self) -> u64 {
-        self as _
-    }
-
     const fn from_bits(value: u64) -> Self {
         match value {
             0 => Self::No,
@@ -276,7 +271,6 @@ impl Display for Info {
 #[bitfield(u64)]
 #[derive(FromBytes, IntoBytes)]
 pub struct WifiMacAndInfo {
-    // TODO: There might be some built-in that we can use here.
     // NOTE: Bouffalo Lab MAC prefix is b4:0e:cf
     // https://macaddress.io/macaddress/B4:0E:CF
     #[bits(48)]
@@ -285,6 +279,54 @@ pub struct WifiMacAndInfo {
     pub info: Info,
 }
 
+impl WifiMacAndInfo {
+    pub fn mac(&self) -> MacAddr {
+        MacAddr::from_field(self.mac_addr())
+    }
+}
+
+/// Bouffalo Lab's IEEE OUI, the first three bytes of their MAC addresses
+pub const BOUFFALO_OUI: [u8; 3] = [0xb4, 0x0e, 0xcf];
+
+/// A MAC address, displayed colon separated, e.g. `b4:0e:cf:12:34:56`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    /// From the 48-bit eFuse field, most significant byte first
+    pub fn from_field(v: u64) -> Self {
+        let b = v.to_be_bytes();
+        Self([b[2], b[3], b[4], b[5], b[6], b[7]])
+    }
+
+    pub fn is_bouffalo(&self) -> bool {
+        self.0.starts_with(&BOUFFALO_OUI)
+    }
+}
+
+impl Display for MacAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+impl std::str::FromStr for MacAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let e = || format!("Invalid MAC address {s}, expected e.g. b4:0e:cf:12:34:56");
+        let b: Vec<u8> = s
+            .split(':')
+            .map(|b| match b.len() {
+                2 => u8::from_str_radix(b, 16).map_err(|_| e()),
+                _ => Err(e()),
+            })
+            .collect::<Result<_, _>>()?;
+        b.try_into().map(Self).map_err(|_| e())
+    }
+}
+
 // TODO
 type Key = [u8; 16];
 
@@ -376,7 +418,6 @@ impl Display for BootPinDelay {
             Self::Delay10us => 10,
             Self::Delay100us => 100,
             Self::Delay500us => 500,
-            _ => unreachable!(),
         };
         write!(f, "{delay} microseconds")
     }
@@ -440,6 +481,44 @@ pub struct SwConfig0 {
     pub hbn_jump_disable: bool,
 }
 
+impl SwConfig0 {
+    /// Whether the mask ROM logs, i.e. the `Log` command returns anything;
+    /// `uart_log_reopen` overrides `uart_log_disable`.
+    pub fn log_enabled(&self) -> bool {
+        !self.uart_log_disable() || self.uart_log_reopen()
+    }
+}
+
+/// JTAG pin mapping as selected by `SwConfig0::jtag_cfg`
+pub fn jtag_pins(jtag_cfg: u8) -> &'static str {
+    match jtag_cfg {
+        0 => "GPIO16-19",
+        1 => "GPIO6/7/12/13",
+        _ => "disabled",
+    }
+}
+
+// Offset of `SwConfig0` within `EfuseBlock0`
+pub const SW_CONFIG0_OFFSET: u32 = 0x5c;
+
+/// Build date of the ROM whose USB boot path is broken, as in its log
+pub const USB_BOOT_BROKEN_ROM: &str = "Sep 29 2021 17:07:23";
+
+/// Check whether writing `data` at `address` would enable the USB boot path,
+/// which is broken in ROM version Sep 29 2021 17:07:23 and may brick the board.
+pub fn enables_usb_boot(address: u32, data: &[u8]) -> bool {
+    let Some(o) = SW_CONFIG0_OFFSET.checked_sub(address) else {
+        return false;
+    };
+    let o = o as usize;
+    match data.get(o..o + 4) {
+        Some(w) => {
+            SwConfig0::from_bits(u32::from_le_bytes(w.try_into().unwrap())).usb_boot_enable()
+        }
+        None => false,
+    }
+}
+
 #[bitfield(u32)]
 #[derive(FromBytes, IntoBytes)]
 pub struct SwConfig1 {
@@ -502,27 +581,27 @@ impl Display for SwConfig {
 #[derive(FromBytes, IntoBytes)]
 pub struct Data0Lock {
     #[bits(4)]
-    ef_sec_lifecycle: u8,
+    pub ef_sec_lifecycle: u8,
     #[bits(10)]
     _wr_lock_reserved_0: u16,
-    wr_lock_boot_mode: bool,
-    wr_lock_debug_password: bool,
-    wr_lock_wifi_mac: bool,
-    wr_lock_key_slot_0: bool,
-    wr_lock_key_slot_1: bool,
-    wr_lock_key_slot_2: bool,
-    wr_lock_key_slot_3: bool,
-    wr_lock_sw_usage_0: bool,
-    wr_lock_sw_usage_1: bool,
-    wr_lock_sw_usage_2: bool,
-    wr_lock_sw_usage_3: bool,
-    wr_lock_key_slot_11: bool,
-    rd_lock_debug_password: bool,
-    rd_lock_key_slot_0: bool,
-    rd_lock_key_slot_1: bool,
-    rd_lock_key_slot_2: bool,
-    rd_lock_key_slot_3: bool,
-    rd_lock_key_slot_11: bool,
+    pub wr_lock_boot_mode: bool,
+    pub wr_lock_debug_password: bool,
+    pub wr_lock_wifi_mac: bool,
+    pub wr_lock_key_slot_0: bool,
+    pub wr_lock_key_slot_1: bool,
+    pub wr_lock_key_slot_2: bool,
+    pub wr_lock_key_slot_3: bool,
+    pub wr_lock_sw_usage_0: bool,
+    pub wr_lock_sw_usage_1: bool,
+    pub wr_lock_sw_usage_2: bool,
+    pub wr_lock_sw_usage_3: bool,
+    pub wr_lock_key_slot_11: bool,
+    pub rd_lock_debug_password: bool,
+    pub rd_lock_key_slot_0: bool,
+    pub rd_lock_key_slot_1: bool,
+    pub rd_lock_key_slot_2: bool,
+    pub rd_lock_key_slot_3: bool,
+    pub rd_lock_key_slot_11: bool,
 }
 
 #[bitfield(u32)]
@@ -530,23 +609,23 @@ pub struct Data0Lock {
 pub struct Data1Lock {
     #[bits(15)]
     _reserved: u16,
-    wr_lock_key_slot_4: bool,
-    wr_lock_key_slot_5: bool,
-    wr_lock_key_slot_6: bool,
-    wr_lock_key_slot_7: bool,
-    wr_lock_key_slot_8: bool,
-    wr_lock_key_slot_9: bool,
-    wr_lock_key_slot_10: bool,
+    pub wr_lock_key_slot_4: bool,
+    pub wr_lock_key_slot_5: bool,
+    pub wr_lock_key_slot_6: bool,
+    pub wr_lock_key_slot_7: bool,
+    pub wr_lock_key_slot_8: bool,
+    pub wr_lock_key_slot_9: bool,
+    pub wr_lock_key_slot_10: bool,
     _wr_lock_dat_1_rsvd_0: bool,
     _wr_lock_dat_1_rsvd_1: bool,
     _wr_lock_dat_1_rsvd_2: bool,
-    rd_lock_key_slot_4: bool,
-    rd_lock_key_slot_5: bool,
-    rd_lock_key_slot_6: bool,
-    rd_lock_key_slot_7: bool,
-    rd_lock_key_slot_8: bool,
-    rd_lock_key_slot_9: bool,
-    rd_lock_key_slot_10: bool,
+    pub rd_lock_key_slot_4: bool,
+    pub rd_lock_key_slot_5: bool,
+    pub rd_lock_key_slot_6: bool,
+    pub rd_lock_key_slot_7: bool,
+    pub rd_lock_key_slot_8: bool,
+    pub rd_lock_key_slot_9: bool,
+    pub rd_lock_key_slot_10: bool,
 }
 
 /// https://github.com/bouffalolab/bouffalo_sdk/
@@ -567,10 +646,590 @@ pub struct EfuseBlock0 {
     pub lock: Data0Lock,
 }
 
-impl Display for EfuseBlock0 {
+// Offsets of fields within `EfuseBlock0`
+pub const DEBUG_PASSWORD_OFFSET: u32 = 0x04;
+pub const DATA0_LOCK_OFFSET: u32 = 0x7c;
+
+// Catch layout mistakes at build time; each block is one 0x80 bytes slot.
+const _: () = assert!(std::mem::size_of::<EfuseBlock0>() == 0x80);
+const _: () = assert!(std::mem::offset_of!(EfuseBlock0, sw_config) == SW_CONFIG0_OFFSET as usize);
+const _: () = assert!(std::mem::offset_of!(EfuseBlock0, lock) == DATA0_LOCK_OFFSET as usize);
+const _: () =
+    assert!(std::mem::offset_of!(EfuseBlock0, debug_password1) == DEBUG_PASSWORD_OFFSET as usize);
+
+// Block 1 follows block 0 in the eFuse address space.
+const BLOCK1_OFFSET: u32 = 0x80;
+
+/// eFuse address ranges that are write-locked according to the lock words of
+/// block 0 and 1, with the name of the lock bit
+pub fn write_locked(lock0: Data0Lock, lock1: Data1Lock) -> Vec<(&'static str, Range<u32>)> {
+    use std::mem::offset_of;
+    let field = |o: usize, l: usize| o as u32..(o + l) as u32;
+    let key0 = |o: usize| field(o, 16);
+    let key1 = |o: usize| field(BLOCK1_OFFSET as usize + o, 16);
+    let sw = offset_of!(EfuseBlock0, sw_config);
+    let regions = [
+        (lock0.wr_lock_boot_mode(), "wr_lock_boot_mode", field(0, 4)),
+        (
+            lock0.wr_lock_debug_password(),
+            "wr_lock_debug_password",
+            field(offset_of!(EfuseBlock0, debug_password1), 16),
+        ),
+        (
+            lock0.wr_lock_wifi_mac(),
+            "wr_lock_wifi_mac",
+            field(offset_of!(EfuseBlock0, wifi_mac_x), 8),
+        ),
+        (
+            lock0.wr_lock_key_slot_0(),
+            "wr_lock_key_slot_0",
+            key0(offset_of!(EfuseBlock0, key0)),
+        ),
+        (
+            lock0.wr_lock_key_slot_1(),
+            "wr_lock_key_slot_1",
+            key0(offset_of!(EfuseBlock0, key1)),
+        ),
+        (
+            lock0.wr_lock_key_slot_2(),
+            "wr_lock_key_slot_2",
+            key0(offset_of!(EfuseBlock0, key2)),
+        ),
+        (
+            lock0.wr_lock_key_slot_3(),
+            "wr_lock_key_slot_3",
+            key0(offset_of!(EfuseBlock0, key3)),
+        ),
+        (
+            lock0.wr_lock_sw_usage_0(),
+            "wr_lock_sw_usage_0",
+            field(sw, 4),
+        ),
+        (
+            lock0.wr_lock_sw_usage_1(),
+            "wr_lock_sw_usage_1",
+            field(sw + 4, 4),
+        ),
+        (
+            lock0.wr_lock_sw_usage_2(),
+            "wr_lock_sw_usage_2",
+            field(sw + 8, 4),
+        ),
+        (
+            lock0.wr_lock_sw_usage_3(),
+            "wr_lock_sw_usage_3",
+            field(sw + 12, 4),
+        ),
+        (
+            lock0.wr_lock_key_slot_11(),
+            "wr_lock_key_slot_11",
+            key0(offset_of!(EfuseBlock0, key11)),
+        ),
+        (
+            lock1.wr_lock_key_slot_4(),
+            "wr_lock_key_slot_4",
+            key1(offset_of!(EfuseBlock1, key4)),
+        ),
+        (
+            lock1.wr_lock_key_slot_5(),
+            "wr_lock_key_slot_5",
+            key1(offset_of!(EfuseBlock1, key5)),
+        ),
+        (
+            lock1.wr_lock_key_slot_6(),
+            "wr_lock_key_slot_6",
+            key1(offset_of!(EfuseBlock1, key6)),
+        ),
+        (
+            lock1.wr_lock_key_slot_7(),
+            "wr_lock_key_slot_7",
+            key1(offset_of!(EfuseBlock1, key7)),
+        ),
+        (
+            lock1.wr_lock_key_slot_8(),
+            "wr_lock_key_slot_8",
+            key1(offset_of!(EfuseBlock1, key8)),
+        ),
+        (
+            lock1.wr_lock_key_slot_9(),
+            "wr_lock_key_slot_9",
+            key1(offset_of!(EfuseBlock1, key9)),
+        ),
+        (
+            lock1.wr_lock_key_slot_10(),
+            "wr_lock_key_slot_10",
+            key1(offset_of!(EfuseBlock1, key10)),
+        ),
+    ];
+    let locked = regions.into_iter().filter(|(l, _, _)| *l);
+    locked.map(|(_, n, r)| (n, r)).collect()
+}
+
+/// Field offsets of both blocks, as the tool assumes them
+pub fn layout() -> String {
+    use std::mem::{offset_of, size_of};
+    let block0 = [
+        ("config", offset_of!(EfuseBlock0, config)),
+        ("debug_password1", offset_of!(EfuseBlock0, debug_password1)),
+        ("debug_password2", offset_of!(EfuseBlock0, debug_password2)),
+        ("wifi_mac_x", offset_of!(EfuseBlock0, wifi_mac_x)),
+        ("key0", offset_of!(EfuseBlock0, key0)),
+        ("key1", offset_of!(EfuseBlock0, key1)),
+        ("key2", offset_of!(EfuseBlock0, key2)),
+        ("key3", offset_of!(EfuseBlock0, key3)),
+        ("sw_config", offset_of!(EfuseBlock0, sw_config)),
+        ("key11", offset_of!(EfuseBlock0, key11)),
+        ("lock", offset_of!(EfuseBlock0, lock)),
+    ];
+    let block1 = [
+        ("key4", offset_of!(EfuseBlock1, key4)),
+        ("key5", offset_of!(EfuseBlock1, key5)),
+        ("key6", offset_of!(EfuseBlock1, key6)),
+        ("key7", offset_of!(EfuseBlock1, key7)),
+        ("key8", offset_of!(EfuseBlock1, key8)),
+        ("key9", offset_of!(EfuseBlock1, key9)),
+        ("key10", offset_of!(EfuseBlock1, key10)),
+        ("lock", offset_of!(EfuseBlock1, lock)),
+    ];
+    let fields = |b: &[(&str, usize)]| {
+        let f = b.iter().map(|(n, o)| format!("\n  {o:02x} {n}"));
+        f.collect::<String>()
+    };
+    let s0 = size_of::<EfuseBlock0>();
+    let s1 = size_of::<EfuseBlock1>();
+    let b0 = format!("Block 0, {s0:#x} bytes:{}", fields(&block0));
+    let b1 = format!("Block 1, {s1:#x} bytes:{}", fields(&block1));
+    format!("{b0}\n{b1}")
+}
+
+type LockSetter = fn(Data0Lock, bool) -> Data0Lock;
+
+// Lock bits that `apply_settings` knows, by field name
+const LOCK_SETTINGS: [(&str, LockSetter); 18] = [
+    ("wr_lock_boot_mode", |l, v| l.with_wr_lock_boot_mode(v)),
+    ("wr_lock_debug_password", |l, v| {
+        l.with_wr_lock_debug_password(v)
+    }),
+    ("wr_lock_wifi_mac", |l, v| l.with_wr_lock_wifi_mac(v)),
+    ("wr_lock_key_slot_0", |l, v| l.with_wr_lock_key_slot_0(v)),
+    ("wr_lock_key_slot_1", |l, v| l.with_wr_lock_key_slot_1(v)),
+    ("wr_lock_key_slot_2", |l, v| l.with_wr_lock_key_slot_2(v)),
+    ("wr_lock_key_slot_3", |l, v| l.with_wr_lock_key_slot_3(v)),
+    ("wr_lock_sw_usage_0", |l, v| l.with_wr_lock_sw_usage_0(v)),
+    ("wr_lock_sw_usage_1", |l, v| l.with_wr_lock_sw_usage_1(v)),
+    ("wr_lock_sw_usage_2", |l, v| l.with_wr_lock_sw_usage_2(v)),
+    ("wr_lock_sw_usage_3", |l, v| l.with_wr_lock_sw_usage_3(v)),
+    ("wr_lock_key_slot_11", |l, v| l.with_wr_lock_key_slot_11(v)),
+    ("rd_lock_debug_password", |l, v| {
+        l.with_rd_lock_debug_password(v)
+    }),
+    ("rd_lock_key_slot_0", |l, v| l.with_rd_lock_key_slot_0(v)),
+    ("rd_lock_key_slot_1", |l, v| l.with_rd_lock_key_slot_1(v)),
+    ("rd_lock_key_slot_2", |l, v| l.with_rd_lock_key_slot_2(v)),
+    ("rd_lock_key_slot_3", |l, v| l.with_rd_lock_key_slot_3(v)),
+    ("rd_lock_key_slot_11", |l, v| l.with_rd_lock_key_slot_11(v)),
+];
+
+/// Apply settings from a flat JSON object to block 0, e.g. on top of the
+/// chip's current fuses. `secure_boot_enable` and `spi_flash_aes_mode` take
+/// variant names as in `json`, `jtag_cfg` a number (see `jtag_pins`), and
+/// the `Data0Lock` bits such as `wr_lock_boot_mode` `true` or `false`.
+pub fn apply_settings(b: &mut EfuseBlock0, settings: &str) -> Result<(), String> {
+    use crate::json::Value;
+    for (k, v) in crate::json::parse_object(settings)? {
+        let mut cfg = b.config;
+        let mut sw = b.sw_config;
+        let mut lock = b.lock;
+        match (k.as_str(), v) {
+            ("secure_boot_enable", Value::String(s)) => {
+                let m = match s.as_str() {
+                    "No" => SecureBootEnable::No,
+                    "X1" => SecureBootEnable::X1,
+                    "X2" => SecureBootEnable::X2,
+                    "X3" => SecureBootEnable::X3,
+                    _ => return Err(format!("Unknown secure boot mode {s}, use No or X1-X3")),
+                };
+                cfg.set_secure_boot_enable(m);
+            }
+            ("spi_flash_aes_mode", Value::String(s)) => {
+                let m = match s.as_str() {
+                    "No" => AesMode::No,
+                    "Aes128" => AesMode::Aes128,
+                    "Aes192" => AesMode::Aes192,
+                    "Aes256" => AesMode::Aes256,
+                    _ => return Err(format!("Unknown AES mode {s}, use No or Aes128/192/256")),
+                };
+                cfg.set_spi_flash_aes_mode(m);
+            }
+            ("jtag_cfg", Value::Number(n)) if n < 4 => sw.sw_config0.set_jtag_cfg(n as u8),
+            (k, Value::Bool(v)) => {
+                let Some((_, set)) = LOCK_SETTINGS.iter().find(|(n, _)| *n == k) else {
+                    return Err(format!("Unknown setting {k}"));
+                };
+                lock = set(lock, v);
+            }
+            (k, v) => return Err(format!("Unknown setting or value {k}: {v:?}")),
+        }
+        b.config = cfg;
+        b.sw_config = sw;
+        b.lock = lock;
+    }
+    Ok(())
+}
+
+/// Decode eFuses as read by `protocol::get_efuses` to JSON, keyed by the
+/// struct field names; blocks that are missing are `null`.
+pub fn json(data: &[u8]) -> String {
+    let block = |o: usize| data.get(o..o + 0x80);
+    let b0 = block(0).and_then(|d| EfuseBlock0::read_from_bytes(d).ok());
+    let b1 = block(0x80).and_then(|d| EfuseBlock1::read_from_bytes(d).ok());
+    let b0 = b0.map_or("null".to_string(), |b| crate::json::from_debug(&b));
+    let b1 = b1.map_or("null".to_string(), |b| crate::json::from_debug(&b));
+    format!("{{\"block0\":{b0},\"block1\":{b1}}}")
+}
+
+// Fields worth pointing out in a diff, matched as parts of the field path
+const SECURITY_FIELDS: [&str; 8] = [
+    "secure",
+    "trusted",
+    "sign",
+    "aes",
+    "jtag",
+    "debug",
+    "lock",
+    "lifecycle",
+];
+
+// Flatten `{:#?}` output into (path, value) pairs, one per bitfield or array
+// element, e.g. `config.secure_boot_enable`.
+fn fields(value: &impl Debug) -> Vec<(String, String)> {
+    let debug = format!("{value:#?}");
+    let mut r = Vec::new();
+    // Path components and the next array index at each level
+    let mut path: Vec<(String, usize)> = Vec::new();
+    for l in debug.lines().skip(1) {
+        let l = l.trim().trim_end_matches(',');
+        let (name, v) = match l.split_once(": ") {
+            Some((n, v)) => (n.to_string(), v),
+            None => match path.last_mut() {
+                Some((_, i)) if !l.starts_with(['}', ']', ')']) => {
+                    *i += 1;
+                    ((*i - 1).to_string(), l)
+                }
+                _ => {
+                    path.pop();
+                    continue;
+                }
+            },
+        };
+        if v.ends_with(['{', '[', '(']) {
+            path.push((name, 0));
+            continue;
+        }
+        let p: Vec<&str> = path.iter().map(|(n, _)| n.as_str()).collect();
+        r.push((
+            [p.as_slice(), &[name.as_str()]].concat().join("."),
+            v.to_string(),
+        ));
+    }
+    r
+}
+
+fn diff_fields(block: &str, a: &impl Debug, b: &impl Debug) -> Vec<String> {
+    let (a, b) = (fields(a), fields(b));
+    let d = a.iter().zip(b.iter()).filter(|(a, b)| a.1 != b.1);
+    d.map(|((n, va), (_, vb))| {
+        let security = SECURITY_FIELDS.iter().any(|f| n.contains(f));
+        let mark = if security { " [security]" } else { "" };
+        format!("{block}.{n}: {va} -> {vb}{mark}")
+    })
+    .collect()
+}
+
+/// Compare two eFuse dumps field by field, as read by `protocol::get_efuses`
+/// or just block 0. Block 1 is compared if both dumps contain it.
+pub fn diff(a: &[u8], b: &[u8]) -> Result<Vec<String>, String> {
+    let block = |d: &[u8], o: usize| d.get(o..o + 0x80).map(<[u8]>::to_vec);
+    let b0 = |d: &[u8]| {
+        let e = || format!("eFuse dump too short: {:#x} bytes", d.len());
+        let b = block(d, 0).ok_or_else(e)?;
+        EfuseBlock0::read_from_bytes(&b).map_err(|_| e())
+    };
+    let mut r = diff_fields("block0", &b0(a)?, &b0(b)?);
+    let b1 = |d: &[u8]| block(d, 0x80).and_then(|b| EfuseBlock1::read_from_bytes(&b).ok());
+    if let (Some(a), Some(b)) = (b1(a), b1(b)) {
+        r.extend(diff_fields("block1", &a, &b));
+    }
+    Ok(r)
+}
+
+/// Words at byte offsets where `current` has bits set that `new` does not.
+/// eFuse bits cannot be cleared, so writing `new` would not give `new`.
+pub fn cleared_bits(current: &[u8], new: &[u8]) -> Vec<(usize, u32)> {
+    let words = |d: &[u8]| {
+        d.chunks_exact(4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+            .collect::<Vec<_>>()
+    };
+    let (c, n) = (words(current), words(new));
+    c.iter()
+        .zip(n.iter())
+        .enumerate()
+        .filter(|(_, (c, n))| *c & !*n != 0)
+        .map(|(i, (c, n))| (4 * i, c & !n))
+        .collect()
+}
+
+/// Result of checking eFuse contents against the documented layout
+#[derive(Debug)]
+pub enum Finding {
+    /// Structurally wrong, refuse to write
+    Error(String),
+    /// Valid, but permanent and possibly unwanted
+    Warning(String),
+}
+
+// `Data0Lock::_wr_lock_reserved_0`
+const DATA0_LOCK_RESERVED: u32 = 0x3ff << 4;
+
+/// Check block 0 contents for reserved bits, inconsistent locks and settings
+/// that cannot be undone.
+pub fn check_block0(b: &EfuseBlock0) -> Vec<Finding> {
+    use Finding::{Error, Warning};
+    let mut r = Vec::<Finding>::new();
+    let cfg = b.config;
+    let sw_cfg = b.sw_config;
+    let cfg0 = sw_cfg.sw_config0;
+    let lock = b.lock;
+
+    if lock.into_bits() & DATA0_LOCK_RESERVED != 0 {
+        r.push(Error("Reserved lock bits are set".to_string()));
+    }
+    let keys = [
+        (
+            0,
+            b.key0,
+            lock.wr_lock_key_slot_0(),
+            lock.rd_lock_key_slot_0(),
+        ),
+        (
+            1,
+            b.key1,
+            lock.wr_lock_key_slot_1(),
+            lock.rd_lock_key_slot_1(),
+        ),
+        (
+            2,
+            b.key2,
+            lock.wr_lock_key_slot_2(),
+            lock.rd_lock_key_slot_2(),
+        ),
+        (
+            3,
+            b.key3,
+            lock.wr_lock_key_slot_3(),
+            lock.rd_lock_key_slot_3(),
+        ),
+        (
+            11,
+            b.key11,
+            lock.wr_lock_key_slot_11(),
+            lock.rd_lock_key_slot_11(),
+        ),
+    ];
+    for (n, key, wr, rd) in keys {
+        let empty = key.iter().all(|&k| k == 0);
+        if empty && rd {
+            r.push(Error(format!("Key slot {n} is read-locked but empty")));
+        } else if empty && wr {
+            r.push(Warning(format!("Key slot {n} is write-locked while empty")));
+        }
+    }
+    let (pw1, pw2) = (b.debug_password1, b.debug_password2);
+    if pw1 == 0 && pw2 == 0 && lock.rd_lock_debug_password() {
+        r.push(Error("Debug password is read-locked but empty".to_string()));
+    }
+    if cfg.spi_flash_aes_mode() != AesMode::No && keys.iter().all(|k| k.1 == [0; 16]) {
+        r.push(Error(
+            "Flash encryption is enabled without any key".to_string(),
+        ));
+    }
+
+    if cfg0.media_boot_disable() && cfg0.uart_boot_disable() && !cfg0.usb_boot_enable() {
+        r.push(Error("All boot sources are disabled".to_string()));
+    } else if cfg0.uart_boot_disable() {
+        r.push(Warning(
+            "UART boot is disabled, this tool cannot connect afterwards".to_string(),
+        ));
+    }
+    if cfg0.usb_boot_enable() {
+        r.push(Warning(
+            "USB boot is enabled, which is broken in some ROM versions".to_string(),
+        ));
+    }
+    if cfg.secure_boot_enable() != SecureBootEnable::No {
+        r.push(Warning(
+            "Secure boot is enabled, only signed images will boot".to_string(),
+        ));
+    }
+    if cfg0.trusted_boot() || cfg0.encrypted_trusted_boot() {
+        r.push(Warning(
+            "Trusted boot restricts the bootloader commands".to_string(),
+        ));
+    }
+    if cfg.debug_jtag_0_dis() != 0 || cfg.debug_jtag_1_dis() != 0 {
+        r.push(Warning(
+            "JTAG is disabled or password protected".to_string(),
+        ));
+    }
+    if lock.ef_sec_lifecycle() != 0 {
+        r.push(Warning("The security lifecycle is advanced".to_string()));
+    }
+    r
+}
+
+// `Data1Lock::_reserved` and `_wr_lock_dat_1_rsvd_*`
+const DATA1_LOCK_RESERVED: u32 = 0x7fff | (0x7 << 22);
+
+/// Check block 1 contents for reserved bits and inconsistent key slot locks.
+pub fn check_block1(b: &EfuseBlock1) -> Vec<Finding> {
+    use Finding::{Error, Warning};
+    let mut r = Vec::<Finding>::new();
+    let lock = b.lock;
+
+    if lock.into_bits() & DATA1_LOCK_RESERVED != 0 {
+        r.push(Error("Reserved block 1 lock bits are set".to_string()));
+    }
+    let (r0, r1, r2) = (b._reserved0, b._reserved1, b._reserved2);
+    if r0 != 0 || r1 != 0 || r2 != 0 {
+        r.push(Error("Reserved block 1 words are set".to_string()));
+    }
+    let keys = [
+        (
+            4,
+            b.key4,
+            lock.wr_lock_key_slot_4(),
+            lock.rd_lock_key_slot_4(),
+        ),
+        (
+            5,
+            b.key5,
+            lock.wr_lock_key_slot_5(),
+            lock.rd_lock_key_slot_5(),
+        ),
+        (
+            6,
+            b.key6,
+            lock.wr_lock_key_slot_6(),
+            lock.rd_lock_key_slot_6(),
+        ),
+        (
+            7,
+            b.key7,
+            lock.wr_lock_key_slot_7(),
+            lock.rd_lock_key_slot_7(),
+        ),
+        (
+            8,
+            b.key8,
+            lock.wr_lock_key_slot_8(),
+            lock.rd_lock_key_slot_8(),
+        ),
+        (
+            9,
+            b.key9,
+            lock.wr_lock_key_slot_9(),
+            lock.rd_lock_key_slot_9(),
+        ),
+        (
+            10,
+            b.key10,
+            lock.wr_lock_key_slot_10(),
+            lock.rd_lock_key_slot_10(),
+        ),
+    ];
+    for (n, key, wr, rd) in keys {
+        let empty = key.iter().all(|&k| k == 0);
+        if empty && rd {
+            r.push(Error(format!("Key slot {n} is read-locked but empty")));
+        } else if empty && wr {
+            r.push(Warning(format!("Key slot {n} is write-locked while empty")));
+        }
+    }
+    r
+}
+
+/// What the chip supports, from the `*_dis` bits in `Config`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    pub ai: bool,
+    pub cpu0: bool,
+    /// Also the high bit of the PSRAM info, see `EfuseBlock0::psram`
+    pub cpu1: bool,
+    pub ble2: bool,
+    pub m1542: bool,
+    pub sdu: bool,
+    pub btdm: bool,
+    pub wifi: bool,
+    pub camera: bool,
+    pub m154: bool,
+}
+
+impl Display for Capabilities {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let c = self;
+        let rows = [
+            ("AI", c.ai),
+            ("CPU0 (M0)", c.cpu0),
+            ("CPU1 (D0)", c.cpu1),
+            ("BLE2", c.ble2),
+            ("802.15.4 (2)", c.m1542),
+            ("SDIO (SDU)", c.sdu),
+            ("BT dual mode", c.btdm),
+            ("Wi-Fi", c.wifi),
+            ("Camera", c.camera),
+            ("802.15.4", c.m154),
+        ];
+        for (i, (name, on)) in rows.into_iter().enumerate() {
+            let state = if on { "enabled" } else { "disabled" };
+            let nl = if i > 0 { "\n" } else { "" };
+            write!(f, "{nl}{name:<12} {state}")?;
+        }
+        Ok(())
+    }
+}
+
+impl EfuseBlock0 {
+    pub fn capabilities(&self) -> Capabilities {
+        let cfg = self.config;
+        Capabilities {
+            ai: !cfg.ai_dis(),
+            cpu0: !cfg.cpu0_dis(),
+            cpu1: !cfg.cpu1_dis(),
+            ble2: !cfg.ble2_dis(),
+            m1542: !cfg.m1542_dis(),
+            sdu: !cfg.sdu_dis(),
+            btdm: !cfg.btdm_dis(),
+            wifi: !cfg.wifi_dis(),
+            camera: !cfg.cam_dis(),
+            m154: !cfg.m154_dis(),
+        }
+    }
+
+    /// The PSRAM in the package; the `cpu1_dis` config bit is the high bit of
+    /// the PSRAM info in the MAC word.
+    pub fn psram(&self) -> Psram {
         let cfg = self.config;
         let psram_high = if cfg.cpu1_dis() { 1 } else { 0 };
+        let macx = self.wifi_mac_x;
+        let psram_low = macx.info().psram_low();
+        Psram::from_u64(((psram_high << 2) | psram_low) as u64)
+    }
+}
+
+impl Display for EfuseBlock0 {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let cfg = self.config;
         let cfg = format!("Config: {cfg:#?}");
         let pw1 = self.debug_password1;
         let pw1 = format!("Password 1: {pw1:016x}");
@@ -578,13 +1237,10 @@ impl Display for EfuseBlock0 {
         let pw2 = format!("Password 2: {pw2:016x}");
 
         let macx = self.wifi_mac_x;
-        let mac = macx.mac_addr();
-        let mac = format!("Wi-Fi MAC: {mac:012x}");
+        let mac = format!("Wi-Fi MAC: {}", macx.mac());
 
         let info = macx.info();
-        let psram_low = info.psram_low();
-        let psram = Psram::from_u64(((psram_high << 2) | psram_low) as u64);
-        let psram = format!("PSRAM: {psram}");
+        let psram = format!("PSRAM: {}", self.psram());
 
         let sw_cfg = self.sw_config;
         let sw_cfg = format!("SW config: {sw_cfg}");
@@ -625,6 +1281,9 @@ pub struct EfuseBlock1 {
     pub lock: Data1Lock,
 }
 
+const _: () = assert!(std::mem::size_of::<EfuseBlock1>() == 0x80);
+const _: () = assert!(std::mem::offset_of!(EfuseBlock1, lock) == 0x7c);
+
 impl Display for EfuseBlock1 {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let lock = self.lock;
@@ -643,3 +1302,88 @@ impl Display for EfuseBlock1 {
         write!(f, "{lock}\n{keys0}\n{keys1}")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zerocopy::IntoBytes;
+
+    // Block 0 as the `EfuseRead` command returns it, assembled from the
+    // register offsets in ef_data_0_reg.h rather than from our structs:
+    // 0x14: Wi-Fi MAC b4:0e:cf:12:34:56, info `5c 06` (QFN88 808C, PSRAM 3)
+    // 0x5c: sw_config0, UART log off, JTAG on GPIO6/7/12/13, LDO18 and RC32M
+    //       trimming on
+    // 0x7c: lock, Wi-Fi MAC write-locked
+    // Everything else, including the keys and debug password, is unset.
+    fn block0_dump() -> [u8; 0x80] {
+        let mut d = [0u8; 0x80];
+        d[0x14..0x1c].copy_from_slice(&[0x56, 0x34, 0x12, 0xcf, 0x0e, 0xb4, 0x5c, 0x06]);
+        d[0x5c..0x60].copy_from_slice(&0x00c0_0402u32.to_le_bytes());
+        d[0x7c..0x80].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        d
+    }
+
+    #[test]
+    fn block0_dump_fields() {
+        let b = EfuseBlock0::read_from_bytes(&block0_dump()).unwrap();
+        let (cfg, pw1, pw2) = (b.config, b.debug_password1, b.debug_password2);
+        assert_eq!(cfg.into_bits(), 0);
+        assert_eq!((pw1, pw2), (0, 0));
+
+        let macx = b.wifi_mac_x;
+        assert_eq!(macx.mac().to_string(), "b4:0e:cf:12:34:56");
+        assert!(macx.mac().is_bouffalo());
+        let info = macx.info();
+        assert_eq!(info.package(), Package::QFN88_808C);
+        assert_eq!(info.psram_low(), 3);
+        assert_eq!(info.flash(), Flash::No);
+        assert_eq!(info.version(), 0);
+
+        let sw = b.sw_config;
+        let sw0 = sw.sw_config0;
+        assert!(sw0.uart_log_disable());
+        assert!(!sw0.uart_log_reopen());
+        assert!(!sw0.log_enabled());
+        assert_eq!(sw0.jtag_cfg(), 1);
+        assert!(sw0.ldo_trim_enable());
+        assert!(sw0.trim_enable());
+        assert!(!sw0.usb_boot_enable());
+        assert!(!sw0.trusted_boot());
+        assert_eq!(sw.sw_config1.into_bits(), 0);
+
+        let lock = b.lock;
+        assert!(lock.wr_lock_wifi_mac());
+        assert!(!lock.wr_lock_debug_password());
+        assert!(!lock.rd_lock_debug_password());
+        assert_eq!(lock.ef_sec_lifecycle(), 0);
+
+        for k in [b.key0, b.key1, b.key2, b.key3, b.key11] {
+            assert_eq!(k, [0; 16]);
+        }
+    }
+
+    // Deterministic pseudo-random fill, so that every bit of every field is
+    // exercised without adding a dependency.
+    fn pattern(seed: u32) -> [u8; 0x80] {
+        let mut x = seed;
+        let mut d = [0u8; 0x80];
+        for b in &mut d {
+            x = x.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            *b = (x >> 24) as u8;
+        }
+        d
+    }
+
+    #[test]
+    fn block_round_trip() {
+        let inputs = [block0_dump(), [0; 0x80], [0xff; 0x80]]
+            .into_iter()
+            .chain((0..64).map(pattern));
+        for d in inputs {
+            let mut b0 = EfuseBlock0::read_from_bytes(&d).unwrap();
+            assert_eq!(b0.as_mut_bytes(), d);
+            let mut b1 = EfuseBlock1::read_from_bytes(&d).unwrap();
+            assert_eq!(b1.as_mut_bytes(), d);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+// SPI flash status register layouts, see the respective datasheets and
+// https://github.com/SourceArcade/flashprog/blob/main/include/flashchips.h
+use std::fmt::{Display, Formatter};
+
+// Read status register commands, SR1 (bits 0-7) and SR2 (bits 8-15)
+pub const READ_STATUS_REG1: u8 = 0x05;
+pub const READ_STATUS_REG2: u8 = 0x35;
+// Write SR1 and SR2 in one go, as the vendor flash configs set QE
+pub const WRITE_STATUS_REG: u8 = 0x01;
+
+/// Positions of protection and configuration bits across SR1 and SR2.
+#[derive(Debug)]
+pub struct StatusLayout {
+    pub name: &'static str,
+    pub busy: u8,
+    pub wel: u8,
+    /// Block protect bits, lowest first
+    pub bp: &'static [u8],
+    /// Top/bottom protect
+    pub tb: u8,
+    /// Sector/block protect
+    pub sec: u8,
+    /// Complement protect
+    pub cmp: u8,
+    /// Status register protect 0 and 1
+    pub srp0: u8,
+    pub srp1: u8,
+    /// Quad enable
+    pub qe: u8,
+}
+
+// Winbond W25Q and GigaDevice GD25Q series; GigaDevice calls TB and SEC
+// BP3 and BP4, but they act the same.
+const SR1_SR2_QE_BIT9: StatusLayout = StatusLayout {
+    name: "SR1/SR2",
+    busy: 0,
+    wel: 1,
+    bp: &[2, 3, 4],
+    tb: 5,
+    sec: 6,
+    cmp: 14,
+    srp0: 7,
+    srp1: 8,
+    qe: 9,
+};
+
+pub fn status_layout(manufacturer: u8) -> Option<&'static StatusLayout> {
+    match manufacturer {
+        0xef | 0xc8 => Some(&SR1_SR2_QE_BIT9),
+        _ => None,
+    }
+}
+
+pub fn manufacturer_name(manufacturer: u8) -> &'static str {
+    match manufacturer {
+        0xef => "Winbond",
+        0xc8 => "GigaDevice",
+        _ => "unknown",
+    }
+}
+
+/// Size in bytes for the third JEDEC ID byte. Most vendors use 2^n bytes,
+/// but go on with 0x20 after 0x19 for 512Mbit and up.
+pub fn capacity(code: u8) -> Option<u32> {
+    match code {
+        0x10..=0x19 => Some(1 << code),
+        0x20 => Some(64 * 1024 * 1024),
+        0x21 => Some(128 * 1024 * 1024),
+        _ => None,
+    }
+}
+
+/// Smallest unit SPI NOR flash erases
+pub const SECTOR_SIZE: u32 = 4096;
+
+/// `size` bytes at `offset` rounded out to whole sectors, as start and end
+/// (exclusive), or `None` if the end overflows
+pub fn sector_range(offset: u32, size: u32) -> Option<(u32, u32)> {
+    let start = offset - offset % SECTOR_SIZE;
+    let end = offset
+        .checked_add(size)?
+        .checked_next_multiple_of(SECTOR_SIZE)?;
+    Some((start, end))
+}
+
+/// Human readable size, e.g. `8M` or `512K`
+pub fn format_size(size: u32) -> String {
+    match size {
+        s if s >= 1024 * 1024 && s % (1024 * 1024) == 0 => format!("{}M", s / 1024 / 1024),
+        s if s % 1024 == 0 => format!("{}K", s / 1024),
+        s => format!("{s} bytes"),
+    }
+}
+
+/// Decoded JEDEC manufacturer/device ID
+#[derive(Debug, Clone, Copy)]
+pub struct FlashInfo {
+    pub manufacturer: u8,
+    /// Memory type and capacity code
+    pub device: u16,
+    /// `None` if the capacity code is unknown
+    pub capacity_bytes: Option<u32>,
+}
+
+impl FlashInfo {
+    pub fn from_jedec(id: [u8; 3]) -> Self {
+        Self {
+            manufacturer: id[0],
+            device: u16::from_le_bytes([id[1], id[2]]),
+            capacity_bytes: capacity(id[2]),
+        }
+    }
+
+    /// The raw JEDEC ID bytes
+    pub fn jedec(&self) -> [u8; 3] {
+        let [d0, d1] = self.device.to_le_bytes();
+        [self.manufacturer, d0, d1]
+    }
+
+    pub fn capacity_code(&self) -> u8 {
+        self.device.to_le_bytes()[1]
+    }
+
+    /// Flash size in bytes, or an error if the capacity code is unknown
+    pub fn size(&self) -> Result<u32, String> {
+        let c = self.capacity_code();
+        self.capacity_bytes
+            .ok_or_else(|| format!("Unknown flash capacity code {c:02x}"))
+    }
+}
+
+impl Display for FlashInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let m = self.manufacturer;
+        let manuf = manufacturer_name(m);
+        let device = self.device;
+        let size = match self.capacity_bytes {
+            Some(s) => format_size(s),
+            None => format!("unknown ({:02x})", self.capacity_code()),
+        };
+        write!(
+            f,
+            "Manufacturer: {manuf} ({m:02x}), device: {device:04x}, size: {size}"
+        )
+    }
+}
+
+/// Combined SR1 and SR2 of a flash chip.
+pub struct Status {
+    pub layout: &'static StatusLayout,
+    pub bits: u16,
+    /// Flash size in bytes
+    pub size: u32,
+}
+
+impl Status {
+    fn bit(&self, pos: u8) -> bool {
+        self.bits & (1 << pos) != 0
+    }
+
+    pub fn bp(&self) -> u8 {
+        let bp = self.layout.bp.iter().enumerate();
+        bp.fold(0, |v, (i, &p)| v | ((self.bit(p) as u8) << i))
+    }
+
+    pub fn qe(&self) -> bool {
+        self.bit(self.layout.qe)
+    }
+
+    /// Protected region as (start, length), following the common W25Q scheme.
+    pub fn protected(&self) -> (u32, u32) {
+        let bp = self.bp() as u32;
+        let size = self.size;
+        let len = match bp {
+            0 => 0,
+            7 => size,
+            _ if self.bit(self.layout.sec) => (4096 << (bp - 1)).min(32 * 1024),
+            _ => (size / 64) << (bp - 1),
+        };
+        let start = if self.bit(self.layout.tb) || len == size {
+            0
+        } else {
+            size - len
+        };
+        if !self.bit(self.layout.cmp) {
+            return (start, len);
+        }
+        match (start, len) {
+            (_, 0) => (0, size),
+            (0, l) => (l, size - l),
+            (_, l) => (0, size - l),
+        }
+    }
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let l = self.layout;
+        let bits = self.bits;
+        let sr = format!("{} status registers: {bits:04x}", l.name);
+        let busy = self.bit(l.busy);
+        let wel = self.bit(l.wel);
+        let state = format!("BUSY: {busy}, WEL: {wel}");
+        let bp = self.bp();
+        let tb = self.bit(l.tb);
+        let sec = self.bit(l.sec);
+        let cmp = self.bit(l.cmp);
+        let prot = format!("BP: {bp}, TB: {tb}, SEC: {sec}, CMP: {cmp}");
+        let srp0 = self.bit(l.srp0);
+        let srp1 = self.bit(l.srp1);
+        let qe = self.qe();
+        let cfg = format!("SRP0: {srp0}, SRP1: {srp1}, QE: {qe}");
+        let region = match self.protected() {
+            (_, 0) => "Protected: none".to_string(),
+            (s, l) => format!("Protected: {l:08x} bytes @ {s:08x}"),
+        };
+        write!(f, "{sr}\n{state}\n{prot}\n{cfg}\n{region}")
+    }
+}
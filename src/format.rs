@@ -0,0 +1,313 @@
+// Image file formats for addressed data.
+// https://en.wikipedia.org/wiki/Intel_HEX
+// https://en.wikipedia.org/wiki/SREC_(file_format)
+// https://refspecs.linuxfoundation.org/elf/elf.pdf
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Format {
+    /// Flat binary
+    Raw,
+    /// Intel HEX
+    Ihex,
+    /// Motorola S-record
+    Srec,
+    /// ELF executable, input only
+    Elf,
+}
+
+impl Format {
+    pub fn detect(data: &[u8]) -> Self {
+        match data.first() {
+            _ if data.starts_with(b"\x7fELF") => Self::Elf,
+            Some(b':') => Self::Ihex,
+            Some(b'S') => Self::Srec,
+            _ => Self::Raw,
+        }
+    }
+}
+
+/// Contiguous data at a load address
+#[derive(Clone, Debug)]
+pub struct Chunk {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+// Sort chunks, merge adjacent ones and reject overlaps.
+fn coalesce(mut chunks: Vec<Chunk>) -> Result<Vec<Chunk>, String> {
+    chunks.sort_by_key(|c| c.address);
+    let mut res = Vec::<Chunk>::new();
+    for c in chunks.into_iter().filter(|c| !c.data.is_empty()) {
+        if let Some(last) = res.last_mut() {
+            let end = last.address as u64 + last.data.len() as u64;
+            if (c.address as u64) < end {
+                return Err(format!("Overlapping data @ {:08x}", c.address));
+            }
+            if c.address as u64 == end {
+                last.data.extend_from_slice(&c.data);
+                continue;
+            }
+        }
+        res.push(c);
+    }
+    Ok(res)
+}
+
+fn parse_hex_bytes(s: &str, line: usize) -> Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(format!("Line {line}: invalid hex"));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("Line {line}: {e}"))
+}
+
+fn read_ihex(data: &[u8]) -> Result<Vec<Chunk>, String> {
+    let text = std::str::from_utf8(data).map_err(|e| e.to_string())?;
+    let mut chunks = Vec::<Chunk>::new();
+    let mut upper = 0u32;
+    for (n, l) in text.lines().enumerate().map(|(n, l)| (n + 1, l.trim())) {
+        if l.is_empty() {
+            continue;
+        }
+        let Some(hex) = l.strip_prefix(':') else {
+            return Err(format!("Line {n}: missing start code"));
+        };
+        let b = parse_hex_bytes(hex, n)?;
+        if b.len() < 5 || b.len() != b[0] as usize + 5 {
+            return Err(format!("Line {n}: bad record length"));
+        }
+        if b.iter().fold(0u8, |s, &x| s.wrapping_add(x)) != 0 {
+            return Err(format!("Line {n}: checksum mismatch"));
+        }
+        let offset = u16::from_be_bytes([b[1], b[2]]) as u32;
+        let payload = &b[4..b.len() - 1];
+        match b[3] {
+            0x00 => chunks.push(Chunk {
+                address: upper + offset,
+                data: payload.to_vec(),
+            }),
+            0x01 => break,
+            0x02 if payload.len() == 2 => {
+                upper = (u16::from_be_bytes([payload[0], payload[1]]) as u32) << 4;
+            }
+            0x04 if payload.len() == 2 => {
+                upper = (u16::from_be_bytes([payload[0], payload[1]]) as u32) << 16;
+            }
+            // start addresses
+            0x03 | 0x05 => {}
+            t => return Err(format!("Line {n}: unsupported record type {t:02x}")),
+        }
+    }
+    coalesce(chunks)
+}
+
+fn read_srec(data: &[u8]) -> Result<Vec<Chunk>, String> {
+    let text = std::str::from_utf8(data).map_err(|e| e.to_string())?;
+    let mut chunks = Vec::<Chunk>::new();
+    for (n, l) in text.lines().enumerate().map(|(n, l)| (n + 1, l.trim())) {
+        if l.is_empty() {
+            continue;
+        }
+        let (Some('S'), Some(t)) = (l.chars().next(), l.chars().nth(1)) else {
+            return Err(format!("Line {n}: missing record start"));
+        };
+        let b = parse_hex_bytes(&l[2..], n)?;
+        if b.is_empty() || b.len() != b[0] as usize + 1 {
+            return Err(format!("Line {n}: bad record length"));
+        }
+        if b.iter().fold(0u8, |s, &x| s.wrapping_add(x)) != 0xff {
+            return Err(format!("Line {n}: checksum mismatch"));
+        }
+        let addr_len = match t {
+            '1' => 2,
+            '2' => 3,
+            '3' => 4,
+            // header, record count and start address
+            '0' | '5' | '6' | '7' | '8' | '9' => continue,
+            _ => return Err(format!("Line {n}: unsupported record type S{t}")),
+        };
+        if b.len() < addr_len + 2 {
+            return Err(format!("Line {n}: record too short"));
+        }
+        let address = b[1..=addr_len]
+            .iter()
+            .fold(0u32, |a, &x| (a << 8) | x as u32);
+        chunks.push(Chunk {
+            address,
+            data: b[addr_len + 1..b.len() - 1].to_vec(),
+        });
+    }
+    coalesce(chunks)
+}
+
+const PT_LOAD: u32 = 1;
+
+fn read_elf(data: &[u8]) -> Result<Vec<Chunk>, String> {
+    let get = |o: usize, l: usize| {
+        let end = o.checked_add(l).ok_or("ELF file is truncated")?;
+        data.get(o..end).ok_or("ELF file is truncated")
+    };
+    let u16_at = |o| get(o, 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as usize);
+    let u32_at = |o| get(o, 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()) as u64);
+    let u64_at = |o| get(o, 8).map(|b| u64::from_le_bytes(b.try_into().unwrap()));
+
+    let ident = get(0, 16)?;
+    if ident[5] != 1 {
+        return Err("Only little-endian ELF files are supported".to_string());
+    }
+    let is64 = match ident[4] {
+        1 => false,
+        2 => true,
+        c => return Err(format!("Unknown ELF class {c}")),
+    };
+    let word = |o| if is64 { u64_at(o) } else { u32_at(o) };
+    let (phoff, phentsize, phnum) = if is64 {
+        (u64_at(0x20)? as usize, u16_at(0x36)?, u16_at(0x38)?)
+    } else {
+        (u32_at(0x1c)? as usize, u16_at(0x2a)?, u16_at(0x2c)?)
+    };
+
+    let mut chunks = Vec::<Chunk>::new();
+    for i in 0..phnum {
+        let ph = i
+            .checked_mul(phentsize)
+            .and_then(|o| o.checked_add(phoff))
+            .ok_or("ELF file is truncated")?;
+        // Fields end at most 0x28 into the entry, so adding their offsets can't overflow.
+        ph.checked_add(0x28).ok_or("ELF file is truncated")?;
+        if u32_at(ph)? as u32 != PT_LOAD {
+            continue;
+        }
+        let (offset, paddr, filesz) = if is64 {
+            (word(ph + 0x08)?, word(ph + 0x18)?, word(ph + 0x20)?)
+        } else {
+            (word(ph + 0x04)?, word(ph + 0x0c)?, word(ph + 0x10)?)
+        };
+        let Ok(address) = u32::try_from(paddr) else {
+            return Err(format!("Segment {i} address {paddr:x} exceeds 32 bits"));
+        };
+        let (Ok(offset), Ok(filesz)) = (usize::try_from(offset), usize::try_from(filesz)) else {
+            return Err("ELF file is truncated".to_string());
+        };
+        let data = get(offset, filesz)?;
+        chunks.push(Chunk {
+            address,
+            data: data.to_vec(),
+        });
+    }
+    coalesce(chunks)
+}
+
+/// Read addressed chunks from `data`; raw data is placed at `base`.
+pub fn read(data: &[u8], format: Format, base: u32) -> Result<Vec<Chunk>, String> {
+    match format {
+        Format::Raw => Ok(vec![Chunk {
+            address: base,
+            data: data.to_vec(),
+        }]),
+        Format::Ihex => read_ihex(data),
+        Format::Srec => read_srec(data),
+        Format::Elf => read_elf(data),
+    }
+}
+
+// Bytes per data record
+const RECORD_SIZE: usize = 16;
+
+fn hex_record(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02X}")).collect()
+}
+
+fn write_ihex(chunks: &[Chunk]) -> String {
+    let mut r = String::new();
+    let mut upper = None;
+    let mut record = |t: u8, offset: u16, payload: &[u8]| {
+        let mut b = vec![payload.len() as u8];
+        b.extend_from_slice(&offset.to_be_bytes());
+        b.push(t);
+        b.extend_from_slice(payload);
+        let sum = b.iter().fold(0u8, |s, &x| s.wrapping_add(x));
+        b.push(sum.wrapping_neg());
+        r += &format!(":{}\n", hex_record(&b));
+    };
+    for c in chunks {
+        for (i, d) in c.data.chunks(RECORD_SIZE).enumerate() {
+            let a = c.address + (i * RECORD_SIZE) as u32;
+            let u = (a >> 16) as u16;
+            if upper != Some(u) {
+                record(0x04, 0, &u.to_be_bytes());
+                upper = Some(u);
+            }
+            // Split records crossing a 64K boundary.
+            let split = (0x1_0000 - (a & 0xffff) as usize).min(d.len());
+            record(0x00, a as u16, &d[..split]);
+            if split < d.len() {
+                let u = u + 1;
+                record(0x04, 0, &u.to_be_bytes());
+                upper = Some(u);
+                record(0x00, 0, &d[split..]);
+            }
+        }
+    }
+    record(0x01, 0, &[]);
+    r
+}
+
+fn write_srec(chunks: &[Chunk]) -> String {
+    let mut r = String::new();
+    let mut record = |t: char, address: u32, payload: &[u8]| {
+        let addr_len = if t == '0' { 2 } else { 4 };
+        let mut b = vec![(addr_len + payload.len() + 1) as u8];
+        b.extend_from_slice(&address.to_be_bytes()[4 - addr_len..]);
+        b.extend_from_slice(payload);
+        let sum = b.iter().fold(0u8, |s, &x| s.wrapping_add(x));
+        b.push(!sum);
+        r += &format!("S{t}{}\n", hex_record(&b));
+    };
+    record('0', 0, b"bl_boot");
+    for c in chunks {
+        for (i, d) in c.data.chunks(RECORD_SIZE).enumerate() {
+            record('3', c.address + (i * RECORD_SIZE) as u32, d);
+        }
+    }
+    record('7', 0, &[]);
+    r
+}
+
+// Largest raw binary to write, as big as the largest flash these chips take
+const MAX_RAW_SIZE: u64 = 64 * 1024 * 1024;
+
+// Flatten chunks into one binary, filling gaps like erased flash.
+fn write_raw(chunks: &[Chunk]) -> Result<Vec<u8>, String> {
+    let (Some(first), Some(last)) = (chunks.first(), chunks.last()) else {
+        return Ok(Vec::new());
+    };
+    let base = first.address as usize;
+    let span = last.address as u64 + last.data.len() as u64 - base as u64;
+    if span > MAX_RAW_SIZE {
+        let m = MAX_RAW_SIZE / 1024 / 1024;
+        return Err(format!(
+            "Data @ {base:08x} spans {span:#x} bytes, more than {m}M of flash"
+        ));
+    }
+    let mut r = Vec::<u8>::new();
+    for c in chunks {
+        let o = c.address as usize - base;
+        r.resize(o, 0xff);
+        r.extend_from_slice(&c.data);
+    }
+    Ok(r)
+}
+
+/// Write addressed chunks as returned by `read` in the given format.
+pub fn write(chunks: &[Chunk], format: Format) -> Result<Vec<u8>, String> {
+    match format {
+        Format::Raw => write_raw(chunks),
+        Format::Ihex => Ok(write_ihex(chunks).into_bytes()),
+        Format::Srec => Ok(write_srec(chunks).into_bytes()),
+        Format::Elf => Err("Writing ELF files is not supported".to_string()),
+    }
+}
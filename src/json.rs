@@ -0,0 +1,195 @@
+// Minimal JSON output without pulling in serde.
+//
+// The on-flash structs all derive `Debug`, which already decodes every field
+// (and copies packed fields safely), so we turn that output into JSON instead
+// of maintaining a second field list per struct.
+use std::fmt::Debug;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Quote and escape a string
+pub fn string(s: &str) -> String {
+    let mut r = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => r += "\\\"",
+            '\\' => r += "\\\\",
+            '\n' => r += "\\n",
+            c if (c as u32) < 0x20 => r += &format!("\\u{:04x}", c as u32),
+            c => r.push(c),
+        }
+    }
+    r + "\""
+}
+
+// Ends a value in `Debug` output
+const DELIMITERS: &str = ",}])";
+
+fn take_while(chars: &mut Peekable<Chars>, f: impl Fn(char) -> bool) -> String {
+    let mut s = String::new();
+    while let Some(&c) = chars.peek() {
+        if !f(c) {
+            break;
+        }
+        s.push(c);
+        chars.next();
+    }
+    s
+}
+
+/// Convert `{:?}` output of derived `Debug` impls to JSON: structs become
+/// objects without their name, tuples arrays and enum variants strings.
+pub fn from_debug(value: &impl Debug) -> String {
+    let debug = format!("{value:?}");
+    let mut chars = debug.chars().peekable();
+    let mut r = String::new();
+    while let Some(&c) = chars.peek() {
+        match c {
+            '{' | '}' | '[' | ']' | ',' | ':' => {
+                r.push(c);
+                chars.next();
+            }
+            '(' => {
+                r.push('[');
+                chars.next();
+            }
+            ')' => {
+                r.push(']');
+                chars.next();
+            }
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => s.extend(chars.next()),
+                        c => s.push(c),
+                    }
+                }
+                r += &string(&s);
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                // Custom `Debug` impls may add units, e.g. `10 microseconds`.
+                let v = take_while(&mut chars, |c| !DELIMITERS.contains(c));
+                let v = v.trim();
+                if v.parse::<f64>().is_ok() {
+                    r += v;
+                } else {
+                    r += &string(v);
+                }
+            }
+            _ => {
+                let id = take_while(&mut chars, |c| c.is_alphanumeric() || c == '_');
+                if id.is_empty() {
+                    // Not something derived `Debug` produces, keep it as text.
+                    r += &string(&chars.next().unwrap().to_string());
+                    continue;
+                }
+                take_while(&mut chars, char::is_whitespace);
+                match chars.peek() {
+                    // A type name
+                    Some('{') | Some('(') => {}
+                    Some(':') => r += &string(&id),
+                    _ => {
+                        let rest = take_while(&mut chars, |c| !DELIMITERS.contains(c));
+                        let v = format!("{id} {rest}");
+                        let v = v.trim();
+                        if v == "true" || v == "false" {
+                            r += v;
+                        } else {
+                            r += &string(v);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    r
+}
+
+/// A value in a flat JSON object, see `parse_object`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Number(u64),
+    String(String),
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    take_while(chars, char::is_whitespace);
+}
+
+fn expect(chars: &mut Peekable<Chars>, c: char) -> Result<(), String> {
+    skip_whitespace(chars);
+    match chars.next() {
+        Some(n) if n == c => Ok(()),
+        n => Err(format!("Expected '{c}', found {n:?}")),
+    }
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    expect(chars, '"')?;
+    let mut s = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(s),
+            Some('\\') => match chars.next() {
+                Some('n') => s.push('\n'),
+                Some(c @ ('"' | '\\' | '/')) => s.push(c),
+                c => return Err(format!("Unsupported escape {c:?}")),
+            },
+            Some(c) => s.push(c),
+            None => return Err("Unterminated string".to_string()),
+        }
+    }
+}
+
+/// Parse a JSON object whose values are strings, unsigned integers or
+/// booleans, e.g. a settings file. Nested objects and arrays are not
+/// supported.
+pub fn parse_object(s: &str) -> Result<Vec<(String, Value)>, String> {
+    let mut chars = s.chars().peekable();
+    let mut r = Vec::new();
+    expect(&mut chars, '{')?;
+    skip_whitespace(&mut chars);
+    let mut more = chars.peek() != Some(&'}');
+    if !more {
+        chars.next();
+    }
+    while more {
+        let k = parse_string(&mut chars)?;
+        expect(&mut chars, ':')?;
+        skip_whitespace(&mut chars);
+        let v = if chars.peek() == Some(&'"') {
+            Value::String(parse_string(&mut chars)?)
+        } else {
+            let v = take_while(&mut chars, |c| {
+                !DELIMITERS.contains(c) && !c.is_whitespace()
+            });
+            match v.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                v => Value::Number(
+                    v.parse()
+                        .map_err(|_| format!("Unsupported value for {k}: {v}"))?,
+                ),
+            }
+        };
+        r.push((k, v));
+        skip_whitespace(&mut chars);
+        more = match chars.next() {
+            Some(',') => true,
+            Some('}') => false,
+            c => return Err(format!("Expected ',' or '}}', found {c:?}")),
+        };
+    }
+    skip_whitespace(&mut chars);
+    if let Some(c) = chars.next() {
+        return Err(format!("Unexpected {c:?} after the object"));
+    }
+    Ok(r)
+}
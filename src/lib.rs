@@ -0,0 +1,142 @@
+//! Talk to Bouffalo Lab SoC mask ROMs over serial.
+//!
+//! The `bl_boot` command line tool is a thin wrapper around this library. Use
+//! `Device` for the common operations, or the `protocol` functions directly.
+
+#[cfg(feature = "async")]
+pub mod async_protocol;
+pub mod boot;
+pub mod efuses;
+pub mod flash;
+pub mod format;
+mod json;
+pub mod mem_map;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod partition;
+pub mod protocol;
+
+pub use protocol::{Port, ProtocolError, Result};
+
+/// A chip in the mask ROM, connected and synchronized
+pub struct Device {
+    port: Port,
+}
+
+impl Device {
+    /// Open the serial port and do the handshake, e.g. at `protocol::BAUD_RATE`.
+    pub fn open(port: String, baud: u32) -> Result<Self> {
+        let port = protocol::init(port, baud)?;
+        Ok(Self { port })
+    }
+
+    /// Use an already synchronized port.
+    pub fn from_port(port: Port) -> Self {
+        Self { port }
+    }
+
+    /// The underlying port, for the functions in `protocol`
+    pub fn port(&mut self) -> &mut Port {
+        &mut self.port
+    }
+
+    pub fn into_port(self) -> Port {
+        self.port
+    }
+
+    /// Set up the flash and read its JEDEC manufacturer/device ID.
+    pub fn flash_id(&mut self) -> Result<flash::FlashInfo> {
+        protocol::get_flash_id(&mut self.port)
+    }
+
+    /// Read `size` bytes from flash at `offset`.
+    pub fn read_flash(&mut self, offset: u32, size: u32) -> Result<Vec<u8>> {
+        protocol::get_flash_id(&mut self.port)?;
+        protocol::read_flash(&mut self.port, offset, size)
+    }
+
+    /// SHA256 of `size` bytes of flash at `offset`, computed by the ROM.
+    pub fn flash_sha(&mut self, offset: u32, size: u32) -> Result<[u8; 32]> {
+        protocol::get_flash_id(&mut self.port)?;
+        protocol::flash_sha(&mut self.port, offset, size)
+    }
+
+    /// Dump flash to a file, verified against the ROM's SHA256.
+    pub fn dump_flash(
+        &mut self,
+        offset: u32,
+        size: u32,
+        file: &str,
+        progress: protocol::Progress,
+    ) -> Result<()> {
+        protocol::dump_flash(&mut self.port, offset, size, file, true, false, progress)
+    }
+
+    /// Erase the flash as needed and write an image to it.
+    pub fn flash_image(&mut self, data: &[u8], progress: protocol::Progress) -> Result<()> {
+        let retries = protocol::WRITE_RETRIES;
+        protocol::flash_image(&mut self.port, data, None, retries, false, progress)
+    }
+
+    /// Erase the sectors at `offset` and write `data` there, then verify it.
+    pub fn write_flash(
+        &mut self,
+        offset: u32,
+        data: &[u8],
+        progress: protocol::Progress,
+    ) -> Result<()> {
+        let retries = protocol::WRITE_RETRIES;
+        protocol::write_flash(&mut self.port, offset, data, retries, true, progress)
+    }
+
+    /// Erase `size` bytes of flash at `offset`, rounded out to whole sectors.
+    pub fn erase_flash(&mut self, offset: u32, size: u32) -> Result<()> {
+        protocol::erase_flash(&mut self.port, offset, size)
+    }
+
+    /// Compare flash at `offset` with `data`.
+    pub fn verify_flash(
+        &mut self,
+        offset: u32,
+        data: &[u8],
+        progress: protocol::Progress,
+    ) -> Result<()> {
+        protocol::verify_flash(&mut self.port, offset, data, progress)
+    }
+
+    /// Load binaries for the M0, D0 and LP cores to SRAM and execute them.
+    pub fn run(
+        &mut self,
+        m0: Option<Vec<u8>>,
+        d0: Option<Vec<u8>>,
+        lp: Option<Vec<u8>>,
+        addrs: boot::LoadAddresses,
+        ignore: boot::IgnoreChecks,
+    ) -> Result<()> {
+        protocol::run(&mut self.port, m0, d0, lp, addrs, ignore)
+    }
+
+    /// Load a prebuilt image to SRAM and execute it.
+    pub fn run_image(&mut self, image: &boot::Image) -> Result<()> {
+        protocol::run_image(&mut self.port, image)
+    }
+
+    /// Print the serial console until Ctrl-C or `idle` without output, e.g.
+    /// after `run`.
+    pub fn monitor(&mut self, idle: Option<std::time::Duration>) -> Result<()> {
+        protocol::monitor(&mut self.port, idle)
+    }
+
+    /// Log the boot info, flash SHA and eFuses.
+    pub fn get_info(&mut self) -> Result<()> {
+        protocol::get_info(&mut self.port)
+    }
+
+    pub fn efuses(&mut self) -> Result<Vec<u8>> {
+        protocol::get_efuses(&mut self.port)
+    }
+
+    pub fn reset(&mut self) -> Result<()> {
+        protocol::reset(&mut self.port)
+    }
+}
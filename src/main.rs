@@ -1,68 +1,351 @@
-#![allow(unused)]
-use std::thread::sleep;
+use std::fs;
+use std::io::{Read, Seek, Write};
 use std::time::Duration;
-use std::{fs, io::Write};
 
-use clap::{Parser, Subcommand};
-use log::{debug, error, info};
-use zerocopy::FromBytes;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use tracing::{debug, error, info, warn};
+use zerocopy::{FromBytes, IntoBytes};
 
-mod boot;
-mod efuses;
-mod mem_map;
-mod protocol;
+use bl_boot::{boot, efuses, flash, format, protocol, ProtocolError};
 
-const PORT: &str = "/dev/ttyUSB1";
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Chip {
+    Bl808,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    /// JSON on stdout, with the struct field names as keys
+    Json,
+}
 
 #[derive(Debug, Subcommand)]
 enum Command {
     /// Identify a SPI flash on the board (JEDEC ID).
     FlashId {
-        #[clap(long, short, action, default_value = PORT)]
-        port: String,
+        /// Print the result as JSON on stdout, the log still goes to stderr
+        #[clap(long, value_enum, default_value = "text")]
+        format: OutputFormat,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Decode the protection bits of a SPI flash's status registers.
+    #[clap(visible_alias = "flash-status")]
+    FlashProtect {
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Set the quad enable bit in the flash status registers.
+    SetFlashQe {
+        /// Clear the bit instead
+        #[clap(long, action)]
+        disable: bool,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Report flash usage according to the partition table.
+    FlashUsage {
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
     },
     /// Dump content of a SPI flash on the board.
     DumpFlash {
-        #[clap(long, short, action, default_value = PORT)]
-        port: String,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
         #[arg(index = 1, value_parser=clap_num::maybe_hex::<u32>)]
         offset: u32,
         #[arg(index = 2, value_parser=clap_num::maybe_hex::<u32>)]
         size: u32,
         #[arg(index = 3)]
         file_name: String,
+        /// Wait for each response before sending the next read request
+        #[clap(long, action)]
+        no_pipeline: bool,
+        /// Switch to this baud rate after the handshake for the transfer
+        #[clap(long)]
+        fast_baud: Option<u32>,
+        /// Continue a partial dump in the file instead of starting over
+        #[clap(long, action)]
+        resume: bool,
+    },
+    /// Dump the whole flash, sized by the capacity in its JEDEC ID.
+    DumpAll {
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+        file_name: String,
+        /// Wait for each response before sending the next read request
+        #[clap(long, action)]
+        no_pipeline: bool,
+        /// Switch to this baud rate after the handshake for the transfer
+        #[clap(long)]
+        fast_baud: Option<u32>,
+        /// Continue a partial dump in the file instead of starting over
+        #[clap(long, action)]
+        resume: bool,
+    },
+    /// Compare flash with a file, reporting the first differing offset.
+    VerifyFlash {
+        file_name: String,
+        #[arg(long, value_parser=clap_num::maybe_hex::<u32>, default_value_t = 0)]
+        offset: u32,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Print the SHA256 of a flash range as the mask ROM computes it, e.g. to
+    /// compare boards without dumping them.
+    FlashSha {
+        #[arg(index = 1, value_parser=clap_num::maybe_hex::<u32>)]
+        offset: u32,
+        #[arg(index = 2, value_parser=clap_num::maybe_hex::<u32>)]
+        size: u32,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Dump flash as read through the XIP controller, as the CPU sees it.
+    XipRead {
+        #[arg(index = 1, value_parser=clap_num::maybe_hex::<u32>)]
+        offset: u32,
+        #[arg(index = 2, value_parser=clap_num::maybe_hex::<u32>)]
+        size: u32,
+        #[arg(index = 3)]
+        file_name: String,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Erase a flash range, rounded out to 4K sectors.
+    EraseFlash {
+        #[arg(index = 1, value_parser=clap_num::maybe_hex::<u32>)]
+        offset: u32,
+        #[arg(index = 2, value_parser=clap_num::maybe_hex::<u32>)]
+        size: u32,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Write a file to flash at a 4K sector aligned offset, e.g. a partition,
+    /// leaving the rest of the flash as is.
+    WriteFlash {
+        #[arg(index = 1)]
+        file_name: String,
+        #[arg(index = 2, value_parser=clap_num::maybe_hex::<u32>)]
+        offset: u32,
+        /// How often to retry a chunk the ROM fails to write
+        #[clap(long, default_value_t = protocol::WRITE_RETRIES)]
+        write_retries: u32,
+        /// Skip comparing the ROM's SHA256 of the written range
+        #[clap(long, action)]
+        no_verify: bool,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Dump the whole flash and split it into one file per partition slot.
+    ExtractPartitions {
+        /// Output directory, also receives a manifest.txt
+        dir: String,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Read the flashed image and split it into one file per segment.
+    ExtractSegments {
+        /// Output directory, also receives a manifest.txt
+        dir: String,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Read a flash region repeatedly and compare the reads to qualify the link.
+    StressTest {
+        #[arg(index = 1, value_parser=clap_num::maybe_hex::<u32>)]
+        offset: u32,
+        #[arg(index = 2, value_parser=clap_num::maybe_hex::<u32>)]
+        size: u32,
+        /// Number of reads
+        #[clap(long, short, default_value_t = 10)]
+        count: u32,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Try the handshake and a few commands at each candidate baud rate and
+    /// report which work. Resets the board via DTR/RTS before each rate.
+    BaudMatrix {
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Dump the boot header region of a SPI flash on the board to a file.
+    DumpHeader {
+        file_name: String,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Print the chip ID, ROM driver version and eFuse package info.
+    ChipId {
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
     },
     /// Reset the platform
     Reset {
-        #[clap(long, short, action, default_value = PORT)]
-        port: String,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Check whether the mask ROM's logging is enabled, i.e. log will work.
+    LogStatus {
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
     },
     /// Reenable the mask ROM's logging function, necessary for the log command.
     ReenableLog {
-        #[clap(long, short, action, default_value = PORT)]
-        port: String,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
     },
     /// Read out the log from the mask ROM. Needs efuse configuration, see above.
     Log {
-        #[clap(long, short, action, default_value = PORT)]
-        port: String,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
     },
     /// Read fuses in the SoC to a file
     ReadFuses {
         file_name: String,
-        #[clap(long, short, action, default_value = PORT)]
-        port: String,
+        /// Print the result as JSON on stdout, the log still goes to stderr
+        #[clap(long, value_enum, default_value = "text")]
+        format: OutputFormat,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Read fuses in the SoC and save them decoded as JSON, for the records
+    ReadFusesJson {
+        file_name: String,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Read an arbitrary range of fuses in the SoC to a file
+    ReadEfuseRange {
+        #[arg(index = 1, value_parser=clap_num::maybe_hex::<u32>)]
+        base: u32,
+        #[arg(index = 2, value_parser=clap_num::maybe_hex::<u32>)]
+        count: u32,
+        #[arg(index = 3)]
+        file_name: String,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
     },
     /// Burn fuses in the SoC with data read from file, must be 128 (0x80) bytes
+    /// for block 0 or 256 (0x100) bytes for block 0 and 1 (key slots 4-10)
     SetFuses {
         file_name: String,
-        #[clap(long, short, action, default_value = PORT)]
-        port: String,
+        /// Allow enabling USB boot, which is broken in some ROM versions
+        #[clap(long, action)]
+        i_know_usb_boot_may_brick_my_board: bool,
+        /// Try to write regions that the lock bits mark as write-locked
+        #[clap(long, action)]
+        force: bool,
+        /// Write without asking for confirmation
+        #[clap(long, short, action)]
+        yes: bool,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Burn eFuse settings from a JSON file on top of the chip's block 0, e.g.
+    /// `{"secure_boot_enable": "X1", "jtag_cfg": 2, "wr_lock_boot_mode": true}`
+    SetFusesJson {
+        file_name: String,
+        /// Allow enabling USB boot, which is broken in some ROM versions
+        #[clap(long, action)]
+        i_know_usb_boot_may_brick_my_board: bool,
+        /// Try to write regions that the lock bits mark as write-locked
+        #[clap(long, action)]
+        force: bool,
+        /// Write without asking for confirmation
+        #[clap(long, short, action)]
+        yes: bool,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Check an eFuse block 0 (and 1) file for reserved bits, inconsistent
+    /// locks and irreversible settings.
+    ValidateFuses { file_name: String },
+    /// Read the Wi-Fi MAC address.
+    ReadMac {
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Burn the Wi-Fi MAC address, e.g. b4:0e:cf:12:34:56.
+    WriteMac {
+        mac: efuses::MacAddr,
+        /// Allow addresses outside of Bouffalo Lab's OUI b4:0e:cf
+        #[clap(long, action)]
+        force: bool,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Compare two eFuse dumps field by field, e.g. from `read-fuses`.
+    EfuseDiff { file_a: String, file_b: String },
+    /// Show the JTAG pin configuration fuse, optionally set it.
+    Jtag {
+        /// New config; 0: GPIO16-19, 1: GPIO6/7/12/13, 2/3: disabled
+        #[arg(long)]
+        set: Option<u8>,
+        /// Confirm disabling JTAG, which is effectively permanent
+        #[clap(long, action)]
+        yes: bool,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Report whether a debug password is set and locked.
+    DebugPassword {
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Burn the debug password, given as 32 hex digits in eFuse byte order.
+    SetDebugPassword {
+        #[arg(index = 1, value_parser = parse_key)]
+        password: [u8; 16],
+        /// Also read- and write-lock the debug password
+        #[clap(long, action)]
+        lock: bool,
+        /// Confirm burning the debug password, which is permanent
+        #[clap(long, action)]
+        yes: bool,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
     },
     /// Print information on the SoC.
     Info {
-        #[clap(long, short, action, default_value = PORT)]
-        port: String,
+        /// Print the result as JSON on stdout, the log still goes to stderr
+        #[clap(long, value_enum, default_value = "text")]
+        format: OutputFormat,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
     },
     /// Write file(s) to SRAM and execute
     #[clap(verbatim_doc_comment)]
@@ -73,18 +356,338 @@ enum Command {
         d0_binary: Option<String>,
         #[clap(long, short, action)]
         lp_binary: Option<String>,
-        #[clap(long, short, action, default_value = PORT)]
-        port: String,
+        /// Load address for the M0 binary instead of the default
+        #[clap(long, value_parser=clap_num::maybe_hex::<u32>)]
+        m0_addr: Option<u32>,
+        /// Load address for the D0 binary instead of the default
+        #[clap(long, value_parser=clap_num::maybe_hex::<u32>)]
+        d0_addr: Option<u32>,
+        /// Load address for the LP binary instead of the default
+        #[clap(long, value_parser=clap_num::maybe_hex::<u32>)]
+        lp_addr: Option<u32>,
+        /// Have the mask ROM skip the boot header CRC check
+        #[clap(long, action)]
+        ignore_crc: bool,
+        /// Have the mask ROM skip the segments hash check
+        #[clap(long, action)]
+        ignore_hash: bool,
+        /// Stop reading the console after this many seconds without output
+        #[clap(long)]
+        idle_timeout: Option<u64>,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Write a prebuilt image, e.g. from build-image, to SRAM and execute
+    RunFile {
+        file_name: String,
+        /// Stop reading the console after this many seconds without output
+        #[clap(long)]
+        idle_timeout: Option<u64>,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Print the serial console of firmware that is already running.
+    Monitor {
+        /// Baud rate of the firmware's console, defaults to --baud
+        #[clap(long)]
+        console_baud: Option<u32>,
+        /// Stop reading the console after this many seconds without output
+        #[clap(long)]
+        idle_timeout: Option<u64>,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Build an image from file(s) for the respective cores
+    BuildImage {
+        #[clap(long, short, action)]
+        m0_binary: Option<String>,
+        #[clap(long, short, action)]
+        d0_binary: Option<String>,
+        #[clap(long, short, action)]
+        lp_binary: Option<String>,
+        /// Load address for the M0 binary instead of the default
+        #[clap(long, value_parser=clap_num::maybe_hex::<u32>)]
+        m0_addr: Option<u32>,
+        /// Load address for the D0 binary instead of the default
+        #[clap(long, value_parser=clap_num::maybe_hex::<u32>)]
+        d0_addr: Option<u32>,
+        /// Load address for the LP binary instead of the default
+        #[clap(long, value_parser=clap_num::maybe_hex::<u32>)]
+        lp_addr: Option<u32>,
+        /// Length of the encrypted part of the segment data, a multiple of 16
+        #[clap(long, value_parser=clap_num::maybe_hex::<u32>)]
+        aes_region_length: Option<u32>,
+        /// Lock the AES region
+        #[clap(long, action, requires = "aes_region_length")]
+        aes_region_lock: bool,
+        /// eFuse key slot selection (0-3) for the AES region; the header has
+        /// one selection for the whole image, not one per core
+        #[clap(long, requires = "aes_region_length", default_value_t = 0)]
+        key_selection: u8,
+        /// Have the mask ROM skip the boot header CRC check
+        #[clap(long, action)]
+        ignore_crc: bool,
+        /// Have the mask ROM skip the segments hash check
+        #[clap(long, action)]
+        ignore_hash: bool,
+        /// JEDEC ID of the target's flash for the flash config, e.g. ef4018
+        #[clap(long, value_parser = parse_jedec_id, conflicts_with = "detect_flash")]
+        flash_id: Option<[u8; 3]>,
+        /// Read the JEDEC ID from the connected target instead
+        #[clap(long, action)]
+        detect_flash: bool,
+        /// Serial port for --detect-flash, detected among known USB adapters
+        /// if not given
+        #[clap(long, short)]
+        port: Option<String>,
+        /// Output image file
+        file_name: String,
     },
     /// Write a prebuilt image to flash.
     FlashImage {
         /// Image file to flash
         file_name: String,
-        #[clap(long, short, action, default_value = PORT)]
-        port: String,
+        /// Flash even if the file is not a valid boot image
+        #[clap(long, action)]
+        force: bool,
+        /// Flash config to base erase command overrides on, a boot image or a
+        /// raw config with FCFG magic; defaults to the image's own
+        #[clap(long)]
+        flash_config: Option<String>,
+        /// 4K sector erase command, commonly 0x20
+        #[clap(long, value_parser=clap_num::maybe_hex::<u8>)]
+        sector_erase_cmd: Option<u8>,
+        /// 32K block erase command, commonly 0x52
+        #[clap(long, value_parser=clap_num::maybe_hex::<u8>)]
+        blk32k_erase_cmd: Option<u8>,
+        /// 64K block erase command, commonly 0xd8
+        #[clap(long, value_parser=clap_num::maybe_hex::<u8>)]
+        blk64k_erase_cmd: Option<u8>,
+        /// Chip erase command, commonly 0xc7
+        #[clap(long, value_parser=clap_num::maybe_hex::<u8>)]
+        chip_erase_cmd: Option<u8>,
+        /// Switch to this baud rate after the handshake for the transfer
+        #[clap(long)]
+        fast_baud: Option<u32>,
+        /// How often to retry a chunk the ROM fails to write
+        #[clap(long, default_value_t = protocol::WRITE_RETRIES)]
+        write_retries: u32,
+        /// Erase the whole chip first, not only the range of the image
+        #[clap(long, action)]
+        full_erase: bool,
+        /// Read the file chunk by chunk while flashing instead of loading it
+        /// first; skips the image check, which needs the whole file
+        #[clap(long, action)]
+        stream: bool,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Show which cores the image on flash starts, and where.
+    CpuConfigs {
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Check the magic and CRC of the boot header on flash.
+    VerifyHeader {
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
+    },
+    /// Diagnose a board that does not boot, optionally reflash a known-good image.
+    ///
+    /// Resets into the mask ROM via DTR/RTS where the board supports it.
+    Recover {
+        /// Known-good image to erase and reflash with
+        #[clap(long, short, action)]
+        image: Option<String>,
+        /// Confirm erasing and reflashing
+        #[clap(long, action)]
+        yes: bool,
+        /// Serial port, detected among known USB adapters if not given
+        #[clap(long, short)]
+        port: Option<String>,
     },
     /// Parse a flash image.
-    ParseImage { file_name: String },
+    ParseImage {
+        file_name: String,
+        /// Print the decoded header, segments and their offsets as JSON
+        #[clap(long, action)]
+        json: bool,
+        /// Fail on CRC mismatches instead of warning
+        #[clap(long, action)]
+        strict: bool,
+    },
+    /// Write the data of one segment of an image to a file, e.g. to re-sign it
+    ExtractSegment {
+        image: String,
+        /// Segment number, as listed by parse-image
+        index: usize,
+        out_file: String,
+    },
+    /// Print what the tool assumes about a chip: addresses, magic, eFuse layout
+    ChipInfo {
+        #[clap(value_enum, default_value = "bl808")]
+        chip: Chip,
+    },
+    /// Convert an image between raw, Intel HEX, S-record and ELF formats.
+    Convert {
+        in_file: String,
+        out_file: String,
+        /// Output format
+        #[clap(long, short, value_enum)]
+        format: format::Format,
+        /// Input format, detected from the content by default
+        #[clap(long, value_enum)]
+        from: Option<format::Format>,
+        /// Load address for raw input
+        #[clap(long, short, value_parser=clap_num::maybe_hex::<u32>, default_value = "0")]
+        base: u32,
+    },
+}
+
+// Ask on the terminal, anything but "yes" declines.
+fn confirm(question: &str) -> bool {
+    eprint!("{question} Type \"yes\" to continue: ");
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok() && answer.trim() == "yes"
+}
+
+// Refuse eFuse contents that are invalid or enable USB boot without
+// `allow_usb_boot`, and log them decoded. Given the chip's `current` fuses,
+// only bits newly set count, so a chip already booting from USB can be updated.
+fn check_fuse_file(
+    payload: &[u8],
+    current: Option<&[u8]>,
+    allow_usb_boot: bool,
+) -> protocol::Result<()> {
+    let invalid = |e: &str| Err(ProtocolError::InvalidInput(e.to_string()));
+    if payload.len() != 0x80 && payload.len() != 0x100 {
+        return invalid("File must be 128 (0x80) or 256 (0x100) bytes!");
+    }
+    if validate_fuses(payload) > 0 {
+        return invalid("Refusing to write an invalid eFuse file");
+    }
+    let newly_set: Vec<u8> = match current {
+        Some(c) => payload.iter().zip(c).map(|(n, c)| n & !c).collect(),
+        None => payload.to_vec(),
+    };
+    if efuses::enables_usb_boot(0, &newly_set) {
+        if !allow_usb_boot {
+            return invalid("Refusing to enable USB boot, it is broken in ROM version Sep 29 2021");
+        }
+        warn!("Enabling USB boot, which is broken in ROM version Sep 29 2021!");
+    }
+    let (b0, b1) = payload.split_at(0x80);
+    match efuses::EfuseBlock0::read_from_bytes(b0) {
+        Ok(f) => info!("Efuses:\n{f}"),
+        Err(_) => error!("Could not parse efuse data"),
+    }
+    if let Ok(f) = efuses::EfuseBlock1::read_from_bytes(b1) {
+        info!("Efuse block 1:\n{f}");
+    }
+    Ok(())
+}
+
+// Burn `payload` from `source` over the chip's eFuses after showing what
+// changes and asking, unless `yes`. Bits already burned cannot be cleared.
+fn burn_fuses(
+    port: &mut protocol::Port,
+    payload: &[u8],
+    source: &str,
+    force: bool,
+    yes: bool,
+) -> protocol::Result<()> {
+    let current = protocol::read_efuse_range(port, 0, payload.len() as u32)?;
+    let cleared = efuses::cleared_bits(&current, payload);
+    if !cleared.is_empty() {
+        for (o, bits) in cleared {
+            error!("Word @ {o:#04x}: bits {bits:08x} are set on the chip, not in the file");
+        }
+        let e = "eFuse bits cannot be cleared, base the file on the chip's current fuses";
+        return Err(ProtocolError::InvalidInput(e.to_string()));
+    }
+    let d = efuses::diff(&current, payload).map_err(ProtocolError::InvalidInput)?;
+    if d.is_empty() {
+        info!("eFuses already match {source}, nothing to write");
+        return Ok(());
+    }
+    info!("{} field(s) change:\n{}", d.len(), d.join("\n"));
+    if !yes && !protocol::is_dry_run() && !confirm("Burn these eFuses? This cannot be undone.") {
+        info!("Aborted, nothing written");
+        return Ok(());
+    }
+    protocol::set_efuses(port, 0, payload, force)
+}
+
+// Log how an eFuse block 0 file fares against the layout, returning the
+// number of errors
+fn validate_fuses(payload: &[u8]) -> usize {
+    let findings = match payload.len() {
+        0x80 | 0x100 => {
+            let (b0, b1) = payload.split_at(0x80);
+            let b = efuses::EfuseBlock0::read_from_bytes(b0).unwrap();
+            let mut f = efuses::check_block0(&b);
+            if let Ok(b) = efuses::EfuseBlock1::read_from_bytes(b1) {
+                f.extend(efuses::check_block1(&b));
+            }
+            f
+        }
+        _ => {
+            error!("File must be 128 (0x80) or 256 (0x100) bytes!");
+            return 1;
+        }
+    };
+    let mut errors = 0;
+    for f in findings {
+        match f {
+            efuses::Finding::Error(e) => {
+                error!("{e}");
+                errors += 1;
+            }
+            efuses::Finding::Warning(w) => warn!("{w}"),
+        }
+    }
+    errors
+}
+
+fn load_addresses(m0: Option<u32>, d0: Option<u32>, lp: Option<u32>) -> boot::LoadAddresses {
+    let d = boot::LoadAddresses::default();
+    boot::LoadAddresses {
+        m0: m0.unwrap_or(d.m0),
+        d0: d0.unwrap_or(d.d0),
+        lp: lp.unwrap_or(d.lp),
+    }
+}
+
+// Parse a JEDEC ID given as 6 hex digits, manufacturer first
+fn parse_jedec_id(s: &str) -> Result<[u8; 3], String> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    match u32::from_str_radix(s, 16) {
+        Ok(v) if s.len() == 6 => {
+            let [_, m, t, c] = v.to_be_bytes();
+            Ok([m, t, c])
+        }
+        _ => Err("must be 6 hex digits, e.g. ef4018".to_string()),
+    }
+}
+
+// Parse a 128-bit value given as 32 hex digits, in eFuse byte order
+fn parse_key(s: &str) -> Result<[u8; 16], String> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    if s.len() != 32 || !s.is_ascii() {
+        return Err("must be 32 hex digits".to_string());
+    }
+    let mut k = [0u8; 16];
+    for (i, b) in k.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|e| e.to_string())?;
+    }
+    Ok(k)
 }
 
 /// Bouffalo Lab mask ROM loader tool
@@ -94,101 +697,710 @@ struct Cli {
     /// Command to run
     #[command(subcommand)]
     cmd: Command,
+    /// Serial baud rate, lower it for adapters that cannot keep up
+    #[clap(long, global = true, default_value_t = protocol::BAUD_RATE)]
+    baud: u32,
+    /// Search for working handshake parameters and save them to the handshake
+    /// config, which later runs use unless overridden by the options below
+    #[clap(long, global = true, action)]
+    handshake_learn: bool,
+    /// Handshake config file [default: $XDG_CONFIG_HOME/bl_boot/handshake]
+    #[clap(long, global = true)]
+    handshake_config: Option<std::path::PathBuf>,
+    /// Number of `U` sent for auto baud rate detection [default: 32]
+    #[clap(long, global = true)]
+    handshake_sync_len: Option<usize>,
+    /// How often to send the handshake magic [default: 1]
+    #[clap(long, global = true)]
+    handshake_magic_repeats: Option<usize>,
+    /// Delay between the `U` burst and the magic in milliseconds [default: 100]
+    #[clap(long, global = true)]
+    handshake_delay_ms: Option<u64>,
+    /// Try transfers in chunks of up to this many bytes, if the ROM takes them
+    #[clap(long, global = true, default_value_t = 4096)]
+    max_chunk_size: u32,
+    /// Log the commands that would write flash or eFuses or run code, without
+    /// sending them; queries still go to the device
+    #[clap(long, global = true, action)]
+    dry_run: bool,
+    /// How long to wait for a response; erases and eFuse writes get longer
+    #[clap(long, global = true, default_value_t = 5000)]
+    timeout_ms: u64,
+}
+
+// Where the learn mode saves handshake parameters, following the XDG layout
+fn default_handshake_config() -> Option<std::path::PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(d) if !d.is_empty() => d.into(),
+        _ => std::path::Path::new(&std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("bl_boot").join("handshake"))
 }
 
-fn main() -> std::io::Result<()> {
-    let cmd = Cli::parse().cmd;
+fn main() {
+    let cli = Cli::parse();
+    let cmd = cli.cmd;
     // Default to log level "info". Otherwise, you get no "regular" logs.
-    let env = env_logger::Env::default().default_filter_or("info");
+    // Without a tracing subscriber, events go to the `log` backend, and so do
+    // span lifecycle records under `tracing::span`; those only for debugging.
+    let env = env_logger::Env::default().default_filter_or("info,tracing::span=warn");
     env_logger::Builder::from_env(env).init();
 
+    let config = cli.handshake_config.or_else(default_handshake_config);
+    let saved = config
+        .as_deref()
+        .and_then(|c| match protocol::load_handshake(c) {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("Ignoring handshake config {}: {e}", c.display());
+                None
+            }
+        });
+    if let Some(p) = saved {
+        debug!("Loaded handshake parameters: {p}");
+    }
+    let saved = saved.unwrap_or(protocol::HandshakeParams::DEFAULT);
+    let handshake = protocol::HandshakeParams {
+        sync_len: cli.handshake_sync_len.unwrap_or(saved.sync_len),
+        magic_repeats: cli.handshake_magic_repeats.unwrap_or(saved.magic_repeats),
+        delay_ms: cli.handshake_delay_ms.unwrap_or(saved.delay_ms),
+    };
+    protocol::configure_handshake(handshake, cli.handshake_learn, config);
+    protocol::set_dry_run(cli.dry_run);
+    protocol::set_response_timeout(Duration::from_millis(cli.timeout_ms));
+
+    let res =
+        protocol::configure_chunk_size(cli.max_chunk_size).and_then(|()| execute(cmd, cli.baud));
+
+    let stats = &protocol::STATS;
+    if stats.total_retries() > 0 {
+        warn!("{stats}, check the connection");
+    } else {
+        debug!("{stats}");
+    }
+
+    if let Err(e) = res {
+        error!("{e}");
+        std::process::exit(1);
+    }
+}
+
+fn execute(cmd: Command, baud: u32) -> protocol::Result<()> {
     match cmd {
         Command::Run {
             m0_binary,
             d0_binary,
             lp_binary,
+            m0_addr,
+            d0_addr,
+            lp_addr,
+            ignore_crc,
+            ignore_hash,
+            idle_timeout,
             port,
         } => {
-            let m0_bin = m0_binary.map(|f| fs::read(f).unwrap());
-            let d0_bin = d0_binary.map(|f| fs::read(f).unwrap());
-            let lp_bin = lp_binary.map(|f| fs::read(f).unwrap());
+            let addrs = load_addresses(m0_addr, d0_addr, lp_addr);
+            let m0_bin = m0_binary.map(fs::read).transpose()?;
+            let d0_bin = d0_binary.map(fs::read).transpose()?;
+            let lp_bin = lp_binary.map(fs::read).transpose()?;
+            let port = protocol::port_or_detect(port)?;
             info!("Using port {port}");
-            let mut port = protocol::init(port);
-            protocol::run(&mut port, m0_bin, d0_bin, lp_bin);
-            info!("🎉 Done. Now read from serial port...");
-            let mut c = &mut [0u8];
-            loop {
-                match port.read(c) {
-                    Ok(n) => print!("{}", c[0] as char),
-                    _ => sleep(Duration::from_millis(500)),
-                }
+            let mut port = protocol::init(port, baud)?;
+            let ignore = boot::IgnoreChecks {
+                crc: ignore_crc,
+                hash: ignore_hash,
+            };
+            protocol::run(&mut port, m0_bin, d0_bin, lp_bin, addrs, ignore)?;
+            info!("🎉 Done. Now read from serial port, Ctrl-C to exit...");
+            protocol::monitor(&mut port, idle_timeout.map(Duration::from_secs))?;
+        }
+        Command::RunFile {
+            port,
+            file_name,
+            idle_timeout,
+        } => {
+            let f = fs::read(file_name)?;
+            let image = boot::Image::parse(&f).map_err(ProtocolError::InvalidInput)?;
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            protocol::run_image(&mut port, &image)?;
+            info!("🎉 Done. Now read from serial port, Ctrl-C to exit...");
+            protocol::monitor(&mut port, idle_timeout.map(Duration::from_secs))?;
+        }
+        Command::Monitor {
+            console_baud,
+            idle_timeout,
+            port,
+        } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}, Ctrl-C to exit");
+            let mut port = protocol::open(port, console_baud.unwrap_or(baud))?;
+            protocol::monitor(&mut port, idle_timeout.map(Duration::from_secs))?;
+        }
+        Command::BuildImage {
+            m0_binary,
+            d0_binary,
+            lp_binary,
+            m0_addr,
+            d0_addr,
+            lp_addr,
+            aes_region_length,
+            aes_region_lock,
+            key_selection,
+            ignore_crc,
+            ignore_hash,
+            flash_id,
+            detect_flash,
+            port,
+            file_name,
+        } => {
+            let flash_id = if detect_flash {
+                let port = protocol::port_or_detect(port)?;
+                info!("Using port {port}");
+                let mut port = protocol::init(port, baud)?;
+                Some(protocol::get_flash_id(&mut port)?.jedec())
+            } else {
+                flash_id
+            };
+            let addrs = load_addresses(m0_addr, d0_addr, lp_addr);
+            let align = |a, f: String| -> protocol::Result<Vec<u8>> {
+                boot::align_segment(a, fs::read(f)?).map_err(ProtocolError::InvalidInput)
+            };
+            let m0_bin = m0_binary.map(|f| align(addrs.m0, f)).transpose()?;
+            let d0_bin = d0_binary.map(|f| align(addrs.d0, f)).transpose()?;
+            let lp_bin = lp_binary.map(|f| align(addrs.lp, f)).transpose()?;
+            let s1 = m0_bin.as_ref().map(|d| boot::Segment::new(addrs.m0, d));
+            let s2 = d0_bin.as_ref().map(|d| boot::Segment::new(addrs.d0, d));
+            let s3 = lp_bin.as_ref().map(|d| boot::Segment::new(addrs.lp, d));
+            let aes = aes_region_length.map(|length| boot::AesRegion {
+                length,
+                lock: aes_region_lock,
+                key_selection,
+            });
+            let ignore = boot::IgnoreChecks {
+                crc: ignore_crc,
+                hash: ignore_hash,
+            };
+            let mut f = fs::File::create(&file_name)?;
+            if let Err(e) = boot::write_image(&mut f, s1, s2, s3, aes, ignore, flash_id) {
+                drop(f);
+                fs::remove_file(&file_name)?;
+                return Err(ProtocolError::InvalidInput(e));
             }
+            drop(f);
+            let segments: Vec<_> = [s1, s2, s3].into_iter().flatten().collect();
+            boot::check_round_trip(&fs::read(&file_name)?, &segments).map_err(|e| {
+                ProtocolError::VerifyFailed(format!("{file_name} does not read back as built: {e}"))
+            })?;
+            info!("Image reads back as built");
+        }
+        Command::ChipId { port } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            protocol::show_chip_id(&mut port)?;
         }
         Command::Reset { port } => {
+            let port = protocol::port_or_detect(port)?;
             info!("Using port {port}");
-            let mut port = protocol::init(port);
-            protocol::reset(&mut port);
+            let mut port = protocol::init(port, baud)?;
+            protocol::reset(&mut port)?;
         }
         Command::ReenableLog { port } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            protocol::reenable_log(&mut port)?;
+        }
+        Command::LogStatus { port } => {
+            let port = protocol::port_or_detect(port)?;
             info!("Using port {port}");
-            let mut port = protocol::init(port);
-            protocol::reenable_log(&mut port);
+            let mut port = protocol::init(port, baud)?;
+            protocol::check_log(&mut port)?;
         }
         Command::Log { port } => {
+            let port = protocol::port_or_detect(port)?;
             info!("Using port {port}");
-            let mut port = protocol::init(port);
-            protocol::read_log(&mut port);
+            let mut port = protocol::init(port, baud)?;
+            protocol::read_log(&mut port)?;
         }
-        Command::Info { port } => {
+        Command::Info { format, port } => {
+            let port = protocol::port_or_detect(port)?;
             info!("Using port {port}");
-            let mut port = protocol::init(port);
-            protocol::get_info(&mut port);
+            let mut port = protocol::init(port, baud)?;
+            match format {
+                OutputFormat::Text => protocol::get_info(&mut port)?,
+                OutputFormat::Json => println!("{}", protocol::info_json(&mut port)?),
+            }
         }
-        Command::ReadFuses { port, file_name } => {
+        Command::ReadFuses {
+            format,
+            port,
+            file_name,
+        } => {
+            let port = protocol::port_or_detect(port)?;
             info!("Using port {port}");
             let mut f = fs::File::create(file_name)?;
-            let mut port = protocol::init(port);
-            let r = protocol::get_efuses(&mut port);
-            f.write_all(&r);
+            let mut port = protocol::init(port, baud)?;
+            let r = protocol::get_efuses(&mut port)?;
+            f.write_all(&r)?;
+            if format == OutputFormat::Json {
+                println!("{}", efuses::json(&r));
+            }
+        }
+        Command::ReadFusesJson { file_name, port } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            let r = protocol::get_efuses(&mut port)?;
+            fs::write(&file_name, efuses::json(&r) + "\n")?;
+            info!("Wrote decoded eFuses to {file_name}");
+        }
+        Command::ReadEfuseRange {
+            base,
+            count,
+            file_name,
+            port,
+        } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            let r = protocol::read_efuse_range(&mut port, base, count)?;
+            fs::write(file_name, r)?;
         }
-        Command::SetFuses { port, file_name } => {
+        Command::SetFuses {
+            port,
+            file_name,
+            i_know_usb_boot_may_brick_my_board,
+            force,
+            yes,
+        } => {
+            let port = protocol::port_or_detect(port)?;
             info!("Using port {port}");
-            let mut payload = std::fs::read(file_name).unwrap();
-            if payload.len() != 0x80 {
-                panic!("File must be 128 (0x80) bytes!");
+            let payload = fs::read(&file_name)?;
+            let mut port = protocol::init(port, baud)?;
+            let current = protocol::read_efuse_range(&mut port, 0, 0x80)?;
+            let allow_usb_boot = i_know_usb_boot_may_brick_my_board;
+            check_fuse_file(&payload, Some(&current), allow_usb_boot)?;
+            burn_fuses(&mut port, &payload, &file_name, force, yes)?;
+        }
+        Command::SetFusesJson {
+            file_name,
+            i_know_usb_boot_may_brick_my_board,
+            force,
+            yes,
+            port,
+        } => {
+            let settings = fs::read_to_string(&file_name)?;
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            let current = protocol::read_efuse_range(&mut port, 0, 0x80)?;
+            let mut b = efuses::EfuseBlock0::read_from_bytes(&current).map_err(|_| {
+                let n = current.len();
+                ProtocolError::InvalidResponse(format!("Unexpected eFuse block 0 size: {n}"))
+            })?;
+            efuses::apply_settings(&mut b, &settings).map_err(ProtocolError::InvalidInput)?;
+            let payload = b.as_mut_bytes().to_vec();
+            let allow_usb_boot = i_know_usb_boot_may_brick_my_board;
+            check_fuse_file(&payload, Some(&current), allow_usb_boot)?;
+            burn_fuses(&mut port, &payload, &file_name, force, yes)?;
+        }
+        Command::ValidateFuses { file_name } => {
+            let payload = fs::read(file_name)?;
+            match validate_fuses(&payload) {
+                0 => info!("eFuse file is valid"),
+                n => {
+                    let e = format!("eFuse file has {n} error(s)");
+                    return Err(ProtocolError::InvalidInput(e));
+                }
+            }
+        }
+        Command::EfuseDiff { file_a, file_b } => {
+            let a = fs::read(&file_a)?;
+            let b = fs::read(&file_b)?;
+            let d = efuses::diff(&a, &b).map_err(ProtocolError::InvalidInput)?;
+            if d.is_empty() {
+                info!("{file_a} and {file_b} match");
+            } else {
+                let n = d.len();
+                info!(
+                    "{n} field(s) differ ({file_a} -> {file_b}):\n{}",
+                    d.join("\n")
+                );
             }
-            match efuses::EfuseBlock0::read_from_bytes(&payload) {
-                Ok(f) => info!("Efuses:\n{f}"),
-                Err(e) => error!("Could not parse efuse data"),
+        }
+        Command::ReadMac { port } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            let mac = protocol::read_mac(&mut port)?;
+            info!("Wi-Fi MAC: {mac}");
+        }
+        Command::WriteMac { mac, force, port } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            protocol::write_mac(&mut port, mac, force)?;
+        }
+        Command::Jtag { set, yes, port } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            protocol::jtag(&mut port, set, yes)?;
+        }
+        Command::DebugPassword { port } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            protocol::get_debug_password(&mut port)?;
+        }
+        Command::SetDebugPassword {
+            password,
+            lock,
+            yes,
+            port,
+        } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            protocol::set_debug_password(&mut port, password, lock, yes)?;
+        }
+        Command::FlashId { format, port } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            match format {
+                OutputFormat::Text => {
+                    protocol::get_info(&mut port)?;
+                    protocol::get_flash_id(&mut port)?;
+                }
+                OutputFormat::Json => {
+                    let id = protocol::get_flash_id(&mut port)?;
+                    println!("{}", protocol::flash_id_json(&id));
+                }
             }
-            let mut port = protocol::init(port);
-            protocol::set_efuses(&mut port, 0, &payload);
         }
-        Command::FlashId { port } => {
+        Command::FlashProtect { port } => {
+            let port = protocol::port_or_detect(port)?;
             info!("Using port {port}");
-            let mut port = protocol::init(port);
-            protocol::get_info(&mut port);
-            protocol::get_flash_id(&mut port);
+            let mut port = protocol::init(port, baud)?;
+            protocol::get_flash_protect(&mut port)?;
+        }
+        Command::SetFlashQe { disable, port } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            protocol::set_flash_qe(&mut port, !disable)?;
+        }
+        Command::FlashUsage { port } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            protocol::flash_usage(&mut port)?;
         }
         Command::DumpFlash {
             port,
             offset,
             size,
             file_name,
+            no_pipeline,
+            fast_baud,
+            resume,
+        } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            if let Some(b) = fast_baud {
+                protocol::change_rate(&mut port, b)?;
+            }
+            let progress = &mut protocol::log_progress;
+            protocol::dump_flash(
+                &mut port,
+                offset,
+                size,
+                &file_name,
+                !no_pipeline,
+                resume,
+                progress,
+            )?;
+        }
+        Command::DumpAll {
+            port,
+            file_name,
+            no_pipeline,
+            fast_baud,
+            resume,
+        } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            if let Some(b) = fast_baud {
+                protocol::change_rate(&mut port, b)?;
+            }
+            let id = protocol::get_flash_id(&mut port)?;
+            let size = id.size().map_err(ProtocolError::InvalidResponse)?;
+            info!("Dump full flash, {}", flash::format_size(size));
+            let progress = &mut protocol::log_progress;
+            protocol::dump_flash(
+                &mut port,
+                0,
+                size,
+                &file_name,
+                !no_pipeline,
+                resume,
+                progress,
+            )?;
+        }
+        Command::VerifyFlash {
+            file_name,
+            offset,
+            port,
+        } => {
+            let data = fs::read(&file_name)?;
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            let progress = &mut protocol::log_progress;
+            protocol::verify_flash(&mut port, offset, &data, progress)?;
+            info!("Flash @ {offset:08x} matches {file_name}");
+        }
+        Command::FlashSha { offset, size, port } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            protocol::get_flash_id(&mut port)?;
+            let sha = protocol::flash_sha(&mut port, offset, size)?;
+            let sha: String = sha.iter().map(|b| format!("{b:02x}")).collect();
+            println!("{sha}");
+        }
+        Command::XipRead {
+            offset,
+            size,
+            file_name,
+            port,
+        } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            let progress = &mut protocol::log_progress;
+            protocol::xip_dump(&mut port, offset, size, &file_name, progress)?;
+        }
+        Command::EraseFlash { offset, size, port } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            protocol::erase_flash(&mut port, offset, size)?;
+        }
+        Command::WriteFlash {
+            file_name,
+            offset,
+            write_retries,
+            no_verify,
+            port,
         } => {
+            let data = fs::read(&file_name)?;
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            let progress = &mut protocol::log_progress;
+            protocol::write_flash(
+                &mut port,
+                offset,
+                &data,
+                write_retries,
+                !no_verify,
+                progress,
+            )?;
+        }
+        Command::ExtractPartitions { dir, port } => {
+            let port = protocol::port_or_detect(port)?;
             info!("Using port {port}");
-            let mut port = protocol::init(port);
-            protocol::dump_flash(&mut port, offset, size, &file_name);
+            let mut port = protocol::init(port, baud)?;
+            protocol::extract_partitions(&mut port, &dir)?;
         }
-        Command::FlashImage { port, file_name } => {
+        Command::ExtractSegments { dir, port } => {
+            let port = protocol::port_or_detect(port)?;
             info!("Using port {port}");
-            let mut port = protocol::init(port);
-            let d = fs::read(file_name).unwrap();
-            protocol::flash_image(&mut port, &d);
+            let mut port = protocol::init(port, baud)?;
+            protocol::extract_segments(&mut port, &dir)?;
         }
-        Command::ParseImage { file_name } => {
-            let f = fs::read(file_name).unwrap();
-            boot::parse_image(&f);
+        Command::StressTest {
+            offset,
+            size,
+            count,
+            port,
+        } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            protocol::stress_test(&mut port, offset, size, count)?;
+        }
+        Command::BaudMatrix { port } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            protocol::baud_matrix(&port);
+        }
+        Command::DumpHeader { port, file_name } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut f = fs::File::create(file_name)?;
+            let mut port = protocol::init(port, baud)?;
+            let h = protocol::read_boot_header(&mut port)?;
+            f.write_all(&h)?;
+        }
+        Command::FlashImage {
+            port,
+            file_name,
+            force,
+            flash_config,
+            sector_erase_cmd,
+            blk32k_erase_cmd,
+            blk64k_erase_cmd,
+            chip_erase_cmd,
+            fast_baud,
+            write_retries,
+            full_erase,
+            stream,
+        } => {
+            let mut file = fs::File::open(&file_name)?;
+            let len = file.metadata()?.len();
+            let Ok(len) = u32::try_from(len) else {
+                let e = format!("{file_name} is too large to flash ({len} bytes)");
+                return Err(ProtocolError::InvalidInput(e));
+            };
+            // With --stream, only the header is needed up front.
+            let mut d = Vec::new();
+            if stream {
+                let header = boot::BOOT_HEADER_SIZE as u64;
+                (&file).take(header).read_to_end(&mut d)?;
+                file.rewind()?;
+            } else {
+                file.read_to_end(&mut d)?;
+            }
+            let erase = boot::EraseCommands {
+                sector: sector_erase_cmd,
+                blk32k: blk32k_erase_cmd,
+                blk64k: blk64k_erase_cmd,
+                chip: chip_erase_cmd,
+            };
+            let fc = if erase.is_empty() {
+                if flash_config.is_some() {
+                    warn!("--flash-config only applies to erase command overrides");
+                }
+                None
+            } else {
+                let base = match flash_config {
+                    Some(f) => fs::read(f)?,
+                    None => d.clone(),
+                };
+                let c = boot::flash_config_with_erase(&base, &erase)
+                    .map_err(ProtocolError::InvalidInput)?;
+                Some(c)
+            };
+            if stream {
+                info!("Streaming {len} bytes from {file_name}, image not checked");
+            } else {
+                match boot::check_image(&d) {
+                    Ok(()) => info!("Image is valid"),
+                    Err(e) if force => warn!("{e}, flashing anyway"),
+                    Err(e) => {
+                        let e = format!("{e}, use --force to flash anyway");
+                        return Err(ProtocolError::InvalidInput(e));
+                    }
+                }
+            }
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            if let Some(b) = fast_baud {
+                protocol::change_rate(&mut port, b)?;
+            }
+            let progress = &mut protocol::log_progress;
+            let (fc, retries) = (fc.as_deref(), write_retries);
+            if stream {
+                let f = &mut file;
+                protocol::flash_image_from(&mut port, f, len, fc, retries, full_erase, progress)?;
+            } else {
+                protocol::flash_image(&mut port, &d, fc, retries, full_erase, progress)?;
+            }
+        }
+        Command::CpuConfigs { port } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            protocol::show_cpu_configs(&mut port)?;
+        }
+        Command::VerifyHeader { port } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let mut port = protocol::init(port, baud)?;
+            protocol::get_flash_id(&mut port)?;
+            protocol::verify_boot_header(&mut port)?;
+            info!("Boot header on flash: OK");
+        }
+        Command::Recover { image, yes, port } => {
+            let port = protocol::port_or_detect(port)?;
+            info!("Using port {port}");
+            let image = image.map(fs::read).transpose()?;
+            protocol::recover(port, baud, image, yes)?;
+        }
+        Command::ParseImage {
+            file_name,
+            json,
+            strict,
+        } => {
+            let f = fs::read(file_name)?;
+            if !json {
+                boot::parse_image(&f, strict).map_err(ProtocolError::InvalidInput)?;
+            } else {
+                let j = boot::image_json(&f).map_err(ProtocolError::InvalidInput)?;
+                println!("{j}");
+            }
+        }
+        Command::ExtractSegment {
+            image,
+            index,
+            out_file,
+        } => {
+            let f = fs::read(image)?;
+            let i = boot::Image::parse(&f).map_err(ProtocolError::InvalidInput)?;
+            let count = i.segments.len();
+            let Some(s) = i.segments.get(index) else {
+                let e = format!("No segment {index}, the image has {count}");
+                return Err(ProtocolError::InvalidInput(e));
+            };
+            let a = s.header.address;
+            fs::write(&out_file, s.data)?;
+            info!(
+                "Segment {index}: {} bytes @ {a:08x} to {out_file}",
+                s.data.len()
+            );
+        }
+        Command::ChipInfo { chip } => {
+            let cmds = Cli::command();
+            let cmds = cmds.get_subcommands().map(|c| c.get_name().to_string());
+            let cmds = cmds.collect::<Vec<_>>().join(", ");
+            info!("{chip:?}\n{}\n\nCommands: {cmds}", protocol::chip_info());
+        }
+        Command::Convert {
+            in_file,
+            out_file,
+            format,
+            from,
+            base,
+        } => {
+            let d = fs::read(in_file)?;
+            let from = from.unwrap_or_else(|| format::Format::detect(&d));
+            info!("Converting {from:?} to {format:?}");
+            let chunks = format::read(&d, from, base).map_err(ProtocolError::InvalidInput)?;
+            for c in &chunks {
+                let (a, l) = (c.address, c.data.len());
+                info!("{l:08x} bytes @ {a:08x}");
+            }
+            let out = format::write(&chunks, format).map_err(ProtocolError::InvalidInput)?;
+            fs::write(out_file, out)?;
         }
     }
 
@@ -1,2 +1,59 @@
 pub const OCRAM_BASE: u32 = 0x2202_0000;
+pub const WRAM_BASE: u32 = 0x2203_0000;
 pub const D0_RAM_BASE: u32 = 0x3ef8_0000;
+
+/// A RAM region segments can be loaded to
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub name: &'static str,
+    pub base: u32,
+    pub size: u32,
+}
+
+// Sizes as in the SDK's BL808 linker scripts
+pub const REGIONS: &[Region] = &[
+    Region {
+        name: "OCRAM",
+        base: OCRAM_BASE,
+        size: 0x1_0000,
+    },
+    Region {
+        name: "WRAM",
+        base: WRAM_BASE,
+        size: 0x2_8000,
+    },
+    Region {
+        name: "D0 RAM",
+        base: D0_RAM_BASE,
+        size: 0x8_0000,
+    },
+];
+
+impl Region {
+    pub fn end(&self) -> u32 {
+        self.base + self.size
+    }
+}
+
+/// The region `addr` is in, if any
+pub fn region(addr: u32) -> Option<Region> {
+    let r = REGIONS.iter().find(|r| (r.base..r.end()).contains(&addr));
+    r.copied()
+}
+
+/// End of the RAM from `addr` on, across regions that follow each other,
+/// e.g. OCRAM and WRAM
+pub fn ram_end(addr: u32) -> Option<u32> {
+    let mut end = region(addr)?.end();
+    while let Some(r) = region(end) {
+        end = r.end();
+    }
+    Some(end)
+}
+
+/// The region that `len` bytes at `addr` start in, if they fit into RAM
+pub fn contains(addr: u32, len: u32) -> Option<Region> {
+    let end = ram_end(addr)?;
+    let fits = addr.checked_add(len).is_some_and(|e| e <= end);
+    fits.then(|| region(addr)).flatten()
+}
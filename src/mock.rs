@@ -0,0 +1,222 @@
+// In-memory serial port that answers with scripted responses, to exercise the
+// protocol functions without a board.
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+use crate::protocol::{Port, BAUD_RATE, FL, HALF_SEC, OK};
+
+#[derive(Debug, Default)]
+struct State {
+    // What the protocol can read right now
+    input: VecDeque<u8>,
+    // Scripted responses, each released by the next write to an empty input,
+    // so that draining and clearing the port beforehand does not eat them
+    replies: VecDeque<Vec<u8>>,
+    output: Vec<u8>,
+    baud: u32,
+    timeout: Duration,
+}
+
+/// A `SerialPort` for tests. Clones share the same buffers, so keep one to
+/// script responses and inspect writes while the protocol owns the `Port`.
+#[derive(Clone, Debug)]
+pub struct MockPort {
+    state: Arc<Mutex<State>>,
+}
+
+impl Default for MockPort {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockPort {
+    pub fn new() -> Self {
+        let state = State {
+            baud: BAUD_RATE,
+            timeout: HALF_SEC,
+            ..Default::default()
+        };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// A `Port` sharing this mock's buffers, to pass to `protocol` functions
+    pub fn port(&self) -> Port {
+        Box::new(self.clone())
+    }
+
+    /// Queue raw bytes as the response to the next command.
+    pub fn respond(&self, data: &[u8]) {
+        self.state.lock().unwrap().replies.push_back(data.to_vec());
+    }
+
+    /// Queue `OK`, e.g. for the handshake or a command without response data.
+    pub fn respond_ok(&self) {
+        self.respond(OK);
+    }
+
+    /// Queue `OK` and `data` with its length, as for commands that retrieve.
+    pub fn respond_data(&self, data: &[u8]) {
+        let len = (data.len() as u16).to_le_bytes();
+        self.respond(&[&OK[..], &len, data].concat());
+    }
+
+    /// Queue `FL` and an error code, as when the ROM rejects a command.
+    pub fn respond_error(&self, code: u16) {
+        self.respond(&[&FL[..], &code.to_le_bytes()].concat());
+    }
+
+    /// Take everything written to the port so far.
+    pub fn take_written(&self) -> Vec<u8> {
+        std::mem::take(&mut self.state.lock().unwrap().output)
+    }
+
+    /// Number of scripted responses not yet released
+    pub fn pending(&self) -> usize {
+        self.state.lock().unwrap().replies.len()
+    }
+}
+
+impl Read for MockPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut s = self.state.lock().unwrap();
+        if s.input.is_empty() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        let n = buf.len().min(s.input.len());
+        for (b, i) in buf.iter_mut().zip(s.input.drain(..n)) {
+            *b = i;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MockPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut s = self.state.lock().unwrap();
+        s.output.extend_from_slice(buf);
+        if s.input.is_empty() {
+            if let Some(r) = s.replies.pop_front() {
+                s.input.extend(r);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for MockPort {
+    fn name(&self) -> Option<String> {
+        Some("mock".to_string())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.state.lock().unwrap().baud)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.state.lock().unwrap().timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.state.lock().unwrap().baud = baud_rate;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.state.lock().unwrap().timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(self.state.lock().unwrap().input.len() as u32)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        if matches!(buffer_to_clear, ClearBuffer::Input | ClearBuffer::All) {
+            self.state.lock().unwrap().input.clear();
+        }
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+// reference:
+// https://github.com/bouffalolab/bouffalo_sdk
+// bsp/common/partition/partition.h
+use std::fmt::Display;
+
+use tracing::warn;
+
+use zerocopy::FromBytes;
+use zerocopy_derive::{FromBytes, Immutable, IntoBytes};
+
+use crate::boot::CRC32;
+
+const PARTITION_MAGIC: &[u8; 4] = b"BFPT";
+const PARTITION_ENTRY_MAX: usize = 16;
+
+// Default location of the two partition table copies in flash
+pub const PARTITION_TABLE_ADDR: [u32; 2] = [0xe000, 0xf000];
+pub const PARTITION_TABLE_SIZE: u32 = 0x1000;
+
+#[derive(FromBytes, Immutable, IntoBytes, Clone, Copy, Debug)]
+#[repr(C, packed)]
+struct PartitionTableConfig {
+    magic: [u8; 4],
+    version: u16,
+    entry_count: u16,
+    age: u32,
+    crc32: u32,
+}
+
+const PARTITION_TABLE_CONFIG_SIZE: usize = std::mem::size_of::<PartitionTableConfig>();
+
+#[derive(FromBytes, Immutable, IntoBytes, Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct PartitionEntry {
+    pub kind: u8,
+    pub device: u8,
+    pub active_index: u8,
+    name: [u8; 9],
+    /// Addresses of the A/B slots
+    pub address: [u32; 2],
+    /// Maximum lengths of the A/B slots
+    pub max_len: [u32; 2],
+    pub len: u32,
+    pub age: u32,
+}
+
+const PARTITION_ENTRY_SIZE: usize = std::mem::size_of::<PartitionEntry>();
+
+impl PartitionEntry {
+    pub fn name(&self) -> String {
+        let n = self.name;
+        let end = n.iter().position(|&c| c == 0).unwrap_or(n.len());
+        String::from_utf8_lossy(&n[..end]).to_string()
+    }
+
+    /// Address and maximum length of each distinct slot
+    pub fn slots(&self) -> Vec<(u32, u32)> {
+        let a = self.address;
+        let l = self.max_len;
+        if a[1] == a[0] || l[1] == 0 {
+            vec![(a[0], l[0])]
+        } else {
+            vec![(a[0], l[0]), (a[1], l[1])]
+        }
+    }
+}
+
+impl Display for PartitionEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self.name();
+        let kind = self.kind;
+        let idx = self.active_index;
+        let [a0, a1] = self.address;
+        let [l0, l1] = self.max_len;
+        let len = self.len;
+        write!(
+            f,
+            "{name:9} type {kind:2}, active {idx}: {l0:08x} @ {a0:08x}, {l1:08x} @ {a1:08x}, length {len:08x}"
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PartitionTable {
+    pub version: u16,
+    pub age: u32,
+    pub entries: Vec<PartitionEntry>,
+}
+
+impl PartitionTable {
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let Ok((cfg, rest)) = PartitionTableConfig::read_from_prefix(data) else {
+            return Err("Partition table too short".to_string());
+        };
+        if &cfg.magic != PARTITION_MAGIC {
+            let m = cfg.magic;
+            return Err(format!("Bad partition table magic: {m:02x?}"));
+        }
+        let crc = CRC32.checksum(&data[..PARTITION_TABLE_CONFIG_SIZE - 4]);
+        if crc != cfg.crc32 {
+            return Err(format!("Partition table CRC mismatch, got {crc:08x}"));
+        }
+        let count = cfg.entry_count as usize;
+        if count > PARTITION_ENTRY_MAX {
+            return Err(format!("Too many partition entries: {count}"));
+        }
+        let size = count * PARTITION_ENTRY_SIZE;
+        if rest.len() < size + 4 {
+            return Err("Partition table entries truncated".to_string());
+        }
+        let crc = CRC32.checksum(&rest[..size]);
+        let stored = u32::from_le_bytes(rest[size..size + 4].try_into().unwrap());
+        if crc != stored {
+            return Err(format!("Partition entries CRC mismatch, got {crc:08x}"));
+        }
+        let entries = rest[..size]
+            .chunks_exact(PARTITION_ENTRY_SIZE)
+            .map(|e| PartitionEntry::read_from_bytes(e).unwrap())
+            .collect();
+        Ok(Self {
+            version: cfg.version,
+            age: cfg.age,
+            entries,
+        })
+    }
+}
+
+impl Display for PartitionTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let v = self.version;
+        let age = self.age;
+        write!(f, "Partition table version {v}, age {age}")?;
+        for e in &self.entries {
+            write!(f, "\n{e}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Pick the newest valid table from copies given as (address, data).
+pub fn newest<'a>(
+    copies: impl IntoIterator<Item = (u32, &'a [u8])>,
+) -> Result<PartitionTable, String> {
+    let mut table: Option<PartitionTable> = None;
+    for (a, d) in copies {
+        match PartitionTable::parse(d) {
+            Ok(t) if table.as_ref().is_none_or(|p| t.age > p.age) => table = Some(t),
+            Ok(_) => {}
+            Err(e) => warn!("Partition table @ {a:08x}: {e}"),
+        }
+    }
+    table.ok_or("No valid partition table found".to_string())
+}
@@ -2,30 +2,266 @@ use core::str;
 use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::OnceLock;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use bitfield_struct::bitfield;
-use log::{debug, error, info};
+use sha2::{Digest, Sha256};
+use tracing::{debug, debug_span, error, info, info_span, warn};
 use zerocopy::{FromBytes, IntoBytes};
 use zerocopy_derive::{FromBytes, IntoBytes};
 
-use crate::boot::{BootHeader, Segment, D0_LOAD_ADDR, LP_LOAD_ADDR, M0_LOAD_ADDR};
-use crate::efuses::{EfuseBlock0, EfuseBlock1, SwConfig0};
+use crate::boot::{
+    align_segment, BootHeader, IgnoreChecks, Image, LoadAddresses, Segment, SegmentHeader,
+    BOOT_HEADER_SIZE, CRC32, D0_LOAD_ADDR, LP_LOAD_ADDR, M0_LOAD_ADDR, SEGMENTS_OFFSET,
+    SEGMENT_HEADER_SIZE,
+};
+use crate::efuses::{self, Data0Lock, EfuseBlock0, EfuseBlock1, MacAddr, SwConfig0};
+use crate::flash::{self, FlashInfo};
+use crate::partition::{self, PartitionTable, PARTITION_TABLE_ADDR, PARTITION_TABLE_SIZE};
 
-type Port = std::boxed::Box<dyn serialport::SerialPort>;
+pub type Port = std::boxed::Box<dyn serialport::SerialPort>;
 
 // should be plenty
-const HALF_SEC: Duration = Duration::from_millis(500);
-const BAUD_RATE: u32 = 2_000_000;
+pub(crate) const HALF_SEC: Duration = Duration::from_millis(500);
+pub const BAUD_RATE: u32 = 2_000_000;
+// The ROM detects the baud rate from the `U` burst, which works within these
+// limits. The vendor tool goes up to 2M, faster rates depend on the adapter.
+const MIN_BAUD_RATE: u32 = 9_600;
+const MAX_BAUD_RATE: u32 = 5_000_000;
+const HIGH_BAUD_RATE: u32 = 2_000_000;
 
-pub fn init(port: String) -> Port {
-    let mut port = serialport::new(port, BAUD_RATE)
-        .timeout(HALF_SEC)
-        .open()
-        .expect("Failed to open port {port}");
-    handshake(&mut port);
-    port
+/// Errors talking to the mask ROM
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The port or a file could not be accessed
+    Io(std::io::Error),
+    /// The ROM answered `FL` with an error code
+    CommandFailed(RomError),
+    /// The ROM answered neither `OK` nor `FL`
+    UnexpectedStatus([u8; 2]),
+    /// No complete response arrived in time
+    Timeout,
+    /// The ROM answered, but not with what we expected
+    InvalidResponse(String),
+    /// The request cannot be sent as given
+    InvalidInput(String),
+    /// What was read back differs from what was written
+    VerifyFailed(String),
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "I/O error: {e}"),
+            Self::CommandFailed(e) => write!(f, "Command error {e}"),
+            Self::UnexpectedStatus(s) => {
+                write!(f, "Unexpected status: {s:02x?} (wanted OK / {OK:02x?})")
+            }
+            Self::Timeout => write!(f, "Timed out waiting for a response"),
+            Self::InvalidResponse(e) | Self::InvalidInput(e) => write!(f, "{e}"),
+            Self::VerifyFailed(e) => write!(f, "Verification failed: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ProtocolError {
+    fn from(e: std::io::Error) -> Self {
+        if is_timeout(&e) {
+            Self::Timeout
+        } else {
+            Self::Io(e)
+        }
+    }
+}
+
+impl From<serialport::Error> for ProtocolError {
+    fn from(e: serialport::Error) -> Self {
+        Self::Io(e.into())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ProtocolError>;
+
+// USB serial adapters found on BL808 boards or commonly used with them
+const KNOWN_ADAPTERS: &[(u16, u16, &str)] = &[
+    // also the BL702 bridge on the Sipeed M1s Dock, which emulates it
+    (0x0403, 0x6010, "FTDI FT2232"),
+    (0x0403, 0x6001, "FTDI FT232R"),
+    (0x0403, 0x6014, "FTDI FT232H"),
+    (0x1a86, 0x7523, "CH340"),
+    (0x1a86, 0x55d4, "CH9102"),
+    (0x10c4, 0xea60, "CP210x"),
+];
+
+/// Find the serial port of a known USB adapter, failing if there is none or
+/// more than one.
+pub fn detect_port() -> Result<String> {
+    let mut found = Vec::<(String, &str)>::new();
+    for p in serialport::available_ports()? {
+        let serialport::SerialPortType::UsbPort(u) = &p.port_type else {
+            continue;
+        };
+        if let Some((_, _, name)) = KNOWN_ADAPTERS
+            .iter()
+            .find(|(vid, pid, _)| *vid == u.vid && *pid == u.pid)
+        {
+            debug!("Found {name} at {}", p.port_name);
+            found.push((p.port_name, name));
+        }
+    }
+    match found.as_slice() {
+        [(port, _)] => Ok(port.clone()),
+        [] => Err(ProtocolError::InvalidInput(
+            "No known USB serial adapter found, pass --port".to_string(),
+        )),
+        _ => {
+            let list: Vec<String> = found.iter().map(|(p, n)| format!("{p} ({n})")).collect();
+            Err(ProtocolError::InvalidInput(format!(
+                "Multiple serial ports found, pass one via --port: {}",
+                list.join(", ")
+            )))
+        }
+    }
+}
+
+/// Use the given port, or detect one.
+pub fn port_or_detect(port: Option<String>) -> Result<String> {
+    match port {
+        Some(p) => Ok(p),
+        None => detect_port(),
+    }
+}
+
+/// Check that the mask ROM can detect `baud`, warning if it is unusually high.
+pub fn check_baud(baud: u32) -> Result<()> {
+    if !(MIN_BAUD_RATE..=MAX_BAUD_RATE).contains(&baud) {
+        return Err(ProtocolError::InvalidInput(format!(
+            "Baud rate {baud} is outside of {MIN_BAUD_RATE}..={MAX_BAUD_RATE}"
+        )));
+    }
+    if baud > HIGH_BAUD_RATE {
+        warn!("Baud rate {baud} is above {HIGH_BAUD_RATE}, the handshake may fail or data get corrupted");
+    }
+    Ok(())
+}
+
+pub fn open(port: String, baud: u32) -> Result<Port> {
+    check_baud(baud)?;
+    let p = serialport::new(port, baud).timeout(HALF_SEC).open()?;
+    Ok(p)
+}
+
+pub fn init(port: String, baud: u32) -> Result<Port> {
+    let mut port = open(port, baud)?;
+    handshake(&mut port)?;
+    Ok(port)
+}
+
+/// Reset into the mask ROM on boards that wire DTR to the boot pin and RTS to
+/// the chip enable / reset line, as the vendor tool expects.
+pub fn reset_into_bootloader(port: &mut Port) {
+    debug!("Reset via DTR/RTS");
+    if let Err(e) = port.write_data_terminal_ready(true) {
+        warn!("Cannot set DTR: {e}");
+    }
+    if let Err(e) = port.write_request_to_send(true) {
+        warn!("Cannot set RTS: {e}");
+    }
+    sleep(Duration::from_millis(50));
+    if let Err(e) = port.write_request_to_send(false) {
+        warn!("Cannot clear RTS: {e}");
+    }
+    sleep(Duration::from_millis(100));
+    if let Err(e) = port.write_data_terminal_ready(false) {
+        warn!("Cannot clear DTR: {e}");
+    }
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+// Take the complete characters off the front of `pending`, replacing invalid
+// sequences, and keep a trailing partial one for the next read.
+fn take_utf8(pending: &mut Vec<u8>) -> String {
+    let mut r = String::new();
+    loop {
+        match str::from_utf8(pending) {
+            Ok(s) => {
+                r += s;
+                pending.clear();
+                return r;
+            }
+            Err(e) => {
+                let valid = e.valid_up_to();
+                r += str::from_utf8(&pending[..valid]).unwrap();
+                let Some(l) = e.error_len() else {
+                    pending.drain(..valid);
+                    return r;
+                };
+                r.push(char::REPLACEMENT_CHARACTER);
+                pending.drain(..valid + l);
+            }
+        }
+    }
+}
+
+/// Print what the firmware sends to stdout until Ctrl-C, or until it has been
+/// quiet for `idle`, e.g. after `run`.
+pub fn monitor(port: &mut Port, idle: Option<Duration>) -> Result<()> {
+    INTERRUPTED.store(false, Ordering::Relaxed);
+    let handler = on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: The handler only sets an atomic flag.
+    let prev = unsafe { libc::signal(libc::SIGINT, handler) };
+    let mut stdout = std::io::stdout();
+    let mut pending = Vec::new();
+    let mut buf = [0u8; 256];
+    let mut last = Instant::now();
+    let r = loop {
+        if INTERRUPTED.load(Ordering::Relaxed) {
+            debug!("Interrupted");
+            break Ok(());
+        }
+        if let Some(i) = idle.filter(|i| last.elapsed() >= *i) {
+            info!("No output for {i:?}, stop reading");
+            break Ok(());
+        }
+        match port.read(&mut buf) {
+            Ok(n) => {
+                if n > 0 {
+                    last = Instant::now();
+                }
+                pending.extend_from_slice(&buf[..n]);
+                let s = take_utf8(&mut pending);
+                // Show partial lines right away.
+                if let Err(e) = stdout.write_all(s.as_bytes()).and_then(|()| stdout.flush()) {
+                    break Err(e.into());
+                }
+            }
+            Err(e) if is_timeout(&e) => sleep(Duration::from_millis(10)),
+            Err(e) => {
+                error!("Error reading from serial port: {e}");
+                sleep(HALF_SEC);
+            }
+        }
+    };
+    // SAFETY: Restores whatever was installed before.
+    unsafe { libc::signal(libc::SIGINT, prev) };
+    r
 }
 
 /// TODO: We could split up into two enums to ensure some can only send while
@@ -33,9 +269,10 @@ pub fn init(port: String) -> Port {
 /// Reference: https://github.com/openbouffalo/bflb-mcu-tool
 ///
 /// libs/bflb_eflash_loader.py + libs/bflb_img_loader.py
+// The full command set; variants that nothing sends yet say why.
 #[derive(Eq, PartialEq, Clone, Copy, Debug)]
 #[repr(u8)]
-enum Command {
+pub(crate) enum Command {
     GetChipId = 0x05,
     GetBootInfo = 0x10,
     LoadBootHeader = 0x11,
@@ -46,30 +283,39 @@ enum Command {
     LoadAesIV = 0x16,
     LoadSegHeader = 0x17,
     LoadSegData = 0x18,
-    // no response
+    // status only, with an error code if the image is rejected
     CheckImage = 0x19,
-    // no response
+    // status only, which is not always sent before the image starts
     RunImage = 0x1a,
     ChangeRate = 0x20,
     // no response
     Reset = 0x21,
+    // Sent by the vendor tool around its eflash loader, which is not used here
+    #[allow(dead_code)]
     ClockSet = 0x22,
+    #[allow(dead_code)]
     OptFinish = 0x23,
     FlashErase = 0x30,
     FlashWrite = 0x31,
     FlashRead = 0x32,
+    // `reset` reboots into flash instead
+    #[allow(dead_code)]
     FlashBoot = 0x33,
     FlashXipRead = 0x34,
     FlashSwitchBank = 0x35,
     FlashReadJedecId = 0x36,
     FlashReadStatusReg = 0x37,
     FlashWriteStatusReg = 0x38,
+    // Writes are verified by reading back the SHA-256 instead
+    #[allow(dead_code)]
     FlashWriteCheck = 0x3a,
     // no response
     FlashSetParam = 0x3b,
     FlashChipErase = 0x3c,
     FlashReadSha = 0x3d,
     FlashXipReadSha = 0x3e,
+    // Needs xz-compressed data, which nothing here produces
+    #[allow(dead_code)]
     FlashDecompressWrite = 0x3f,
     // no response
     EfuseWrite = 0x40,
@@ -79,15 +325,46 @@ enum Command {
     FlashXipReadStart = 0x60,
     FlashXipReadFinish = 0x61,
     LogRead = 0x71,
+    // Encrypted eFuse access, which needs an ECDH session first
+    #[allow(dead_code)]
     EfuseSecurityWrite = 0x80,
+    #[allow(dead_code)]
     EfuseSecurityRead = 0x81,
     EcdhGetPk = 0x90,
     EcdhChallenge = 0x91,
 }
 
+impl Command {
+    /// Whether the command changes the flash or eFuses, or loads and runs code
+    pub(crate) fn changes_state(self) -> bool {
+        use Command::*;
+        matches!(
+            self,
+            LoadBootHeader
+                | LoadPublicKey1
+                | LoadPublicKey2
+                | LoadSignature1
+                | LoadSignature2
+                | LoadAesIV
+                | LoadSegHeader
+                | LoadSegData
+                | CheckImage
+                | RunImage
+                | FlashErase
+                | FlashWrite
+                | FlashWriteStatusReg
+                | FlashChipErase
+                | FlashDecompressWrite
+                | EfuseWrite
+                | EfuseWriteMac
+                | EfuseSecurityWrite
+        )
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 #[repr(C, packed)]
-struct CommandPacket {
+pub(crate) struct CommandPacket {
     command: u8,
     size: u16,
 }
@@ -95,7 +372,14 @@ struct CommandPacket {
 const CMD_SIZE: usize = 4;
 
 impl CommandPacket {
-    fn to_slice(self) -> [u8; CMD_SIZE] {
+    pub(crate) fn new(command: Command, data: &[u8]) -> Self {
+        Self {
+            command: command as u8,
+            size: data.len() as u16,
+        }
+    }
+
+    pub(crate) fn to_slice(self) -> [u8; CMD_SIZE] {
         let sz = self.size;
         let l0 = sz as u8;
         let l1 = (sz >> 8) as u8;
@@ -105,134 +389,673 @@ impl CommandPacket {
 }
 
 // Response "okay"
-const OK: &[u8; 2] = b"OK";
+pub(crate) const OK: &[u8; 2] = b"OK";
 // Response "fail"
-const FL: &[u8; 2] = b"FL";
+pub(crate) const FL: &[u8; 2] = b"FL";
+// Response "pending", sent while a long operation like a chip erase runs
+pub(crate) const PD: &[u8; 2] = b"PD";
+
+pub(crate) const CHUNK_SIZE: u32 = 4096;
+// Safe ceiling for larger chunks, well below the 16-bit packet length
+pub const MAX_CHUNK_SIZE: u32 = 16 * 1024;
+
+/// Which part of the ROM an error code comes from, by its high byte
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCategory {
+    Flash,
+    Command,
+    Image,
+    /// Baud rate changes and the debug password
+    Interface,
+    Efuse,
+    Memory,
+    Ecdh,
+    Other,
+}
+
+impl ErrorCategory {
+    fn from_code(code: u16) -> Self {
+        match code >> 8 {
+            0x00 => Self::Flash,
+            0x01 => Self::Command,
+            0x02 => Self::Image,
+            0x03 => Self::Interface,
+            0x04 => Self::Efuse,
+            0x05 => Self::Memory,
+            0x06 => Self::Ecdh,
+            _ => Self::Other,
+        }
+    }
+}
+
+// libs/bflb_utils.py, `eflash_loader_error_code`
+const ROM_ERRORS: &[(u16, &str)] = &[
+    (0x0001, "flash init error"),
+    (0x0002, "flash erase parameter error"),
+    (0x0003, "flash erase error"),
+    (0x0004, "flash write parameter error"),
+    (0x0005, "flash write address error"),
+    (0x0006, "flash write error"),
+    (0x0007, "flash boot parameter error"),
+    (0x0008, "flash set parameter error"),
+    (0x0009, "flash read status register error"),
+    (0x000a, "flash write status register error"),
+    (0x000b, "flash decompress write error"),
+    (0x000c, "flash write XZ error"),
+    (0x000d, "flash switch bank error"),
+    (0x0101, "command ID error"),
+    (0x0102, "command length error"),
+    (0x0103, "command CRC error"),
+    (0x0104, "command sequence error"),
+    (0x0201, "image boot header length error"),
+    (0x0202, "image boot header not loaded"),
+    (0x0203, "image boot header magic error"),
+    (0x0204, "image boot header CRC error"),
+    (
+        0x0205,
+        "fuses expected encryption but none in image boot header",
+    ),
+    (
+        0x0206,
+        "fuses expected signature but none in image boot header",
+    ),
+    (0x0207, "image segment count error"),
+    (0x0208, "image AES IV length error"),
+    (0x0209, "image AES IV CRC error"),
+    (0x020a, "image public key length error"),
+    (0x020b, "image public key CRC error"),
+    (0x020c, "image public key hash error"),
+    (0x020d, "image signature length error"),
+    (0x020e, "image signature CRC error"),
+    (0x020f, "image section header length error"),
+    (0x0210, "image section header CRC error"),
+    (0x0211, "image section header destination error"),
+    (0x0212, "image section data length error"),
+    (0x0213, "image section data decryption error"),
+    (0x0214, "image section data total length error"),
+    (0x0215, "image section data CRC error"),
+    (0x0216, "image half-baked error"),
+    (0x0217, "image hash error"),
+    (0x0218, "image signature parse error"),
+    (0x0219, "image signature error"),
+    (0x021a, "image decryption error"),
+    (0x021b, "image all invalid error"),
+    (0x0301, "interface rate length error"),
+    (0x0302, "interface rate parameter error"),
+    (0x0303, "interface password error"),
+    (0x0304, "interface password closed"),
+    (0x0401, "eFuse write parameter error"),
+    (0x0402, "eFuse write addr error"),
+    (0x0403, "eFuse write error"),
+    (0x0404, "eFuse read parameter error"),
+    (0x0405, "eFuse read addr error"),
+    (0x0406, "eFuse read error"),
+    (0x0407, "eFuse read MAC error"),
+    (0x0408, "eFuse write MAC error"),
+    (0x0501, "memory write parameter error"),
+    (0x0502, "memory write address error"),
+    (0x0503, "memory write error"),
+    (0x0504, "memory read parameter error"),
+    (0x0505, "memory read address error"),
+    (0x0506, "memory read error"),
+    (0x0601, "ECDH parameter error"),
+    (0x0602, "ECDH private key error"),
+    (0x0603, "ECDH shared key error"),
+    (0x0604, "ECDH random value error"),
+    (0x0605, "ECDH decryption error"),
+    (0x0606, "ECDH encryption error"),
+    (0xfffc, "PLL error"),
+    (0xfffd, "invasion error"),
+    (0xfffe, "polling"),
+    (0xffff, "failure"),
+];
+
+/// An error code the ROM sent after `FL`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RomError {
+    pub category: ErrorCategory,
+    pub code: u16,
+    pub message: &'static str,
+}
+
+impl RomError {
+    pub fn from_code(code: u16) -> Self {
+        let message = ROM_ERRORS
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map_or("unknown error", |(_, m)| m);
+        Self {
+            category: ErrorCategory::from_code(code),
+            code,
+            message,
+        }
+    }
+}
+
+impl Display for RomError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (c, m) = (self.code, self.message);
+        write!(f, "{c:04x} ({m})")
+    }
+}
+
+// How long to wait for a response by default
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+// Minimum time for the ROM to program eFuse words
+const EFUSE_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+// Erase time per MiB, close to the worst case of 64K block erases
+const ERASE_TIMEOUT_PER_MIB: Duration = Duration::from_secs(20);
+// A full chip erase takes up to minutes on large flash parts.
+const CHIP_ERASE_TIMEOUT: Duration = Duration::from_secs(300);
+
+static RESPONSE_TIMEOUT_MS: AtomicU64 = AtomicU64::new(RESPONSE_TIMEOUT.as_millis() as u64);
+
+/// Set how long to wait for a response for this session. Long operations
+/// like erasing flash or writing eFuses get at least their own minimum.
+pub fn set_response_timeout(timeout: Duration) {
+    RESPONSE_TIMEOUT_MS.store(timeout.as_millis() as u64, Ordering::Relaxed);
+}
+
+pub fn response_timeout() -> Duration {
+    Duration::from_millis(RESPONSE_TIMEOUT_MS.load(Ordering::Relaxed))
+}
+
+// Time to wait for the status of `command`, scaled by the range for erases
+fn command_timeout(command: Command, data: &[u8]) -> Duration {
+    let t = response_timeout();
+    match command {
+        Command::FlashChipErase => t.max(CHIP_ERASE_TIMEOUT),
+        Command::FlashErase => {
+            let w = |o| {
+                data.get(o..o + 4)
+                    .map_or(0, |w| u32::from_le_bytes(w.try_into().unwrap()))
+            };
+            let mib = w(4).saturating_sub(w(0)).div_ceil(1024 * 1024);
+            t + ERASE_TIMEOUT_PER_MIB * mib
+        }
+        Command::EfuseWrite | Command::EfuseWriteMac => t.max(EFUSE_WRITE_TIMEOUT),
+        _ => t,
+    }
+}
 
-const CHUNK_SIZE: u32 = 4096;
+/// Whether a read error only means that no data has arrived yet
+pub fn is_timeout(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        e.kind(),
+        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+    )
+}
 
-// libs/bflb_utils.py
-fn code_to_msg(code: u16) -> &'static str {
-    match code {
-        0x0102 => "command length error",
-        0x0104 => "command sequence error",
-        0x0204 => "image boot header CRC error",
-        0x0205 => "fuses expected encryption but none in image boot header",
-        0x0210 => "image section header CRC error",
-        0x0217 => "image hash error",
-        0x0405 => "eFuse read addr error",
-        _ => "unknown error",
+// Fill `buf`, waiting through timeouts until `deadline` has passed.
+fn read_full(port: &mut Port, buf: &mut [u8], deadline: Duration) -> std::io::Result<()> {
+    let end = Instant::now() + deadline;
+    let mut n = 0;
+    while n < buf.len() {
+        let c = match port.read(&mut buf[n..]) {
+            Ok(c) => c,
+            Err(e) if is_timeout(&e) => 0,
+            Err(e) => return Err(e),
+        };
+        n += c;
+        if n < buf.len() && Instant::now() >= end {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        if c == 0 {
+            // Do not spin on ports that return WouldBlock immediately.
+            sleep(Duration::from_millis(1));
+        }
     }
+    Ok(())
 }
 
 // Read the status after sending a command.
-fn get_ok(port: &mut Port) -> Result<(), String> {
+fn get_ok(port: &mut Port) -> Result<()> {
+    get_ok_within(port, response_timeout())
+}
+
+fn get_ok_within(port: &mut Port, deadline: Duration) -> Result<()> {
     debug!("Check for command OK");
-    let mut stat = vec![0u8; 2];
-    match port.read(stat.as_mut_slice()) {
-        Ok(n) => debug!("Read status, {n} bytes"),
-        Err(e) => panic!("Error reading status: {e}"),
-    };
-    if stat == FL {
+    let end = Instant::now() + deadline;
+    let mut stat = [0u8; 2];
+    read_full(port, &mut stat, deadline)?;
+    while &stat == PD {
+        debug!("Command pending");
+        let left = end.saturating_duration_since(Instant::now());
+        read_full(port, &mut stat, left)?;
+    }
+    if &stat == FL {
         error!("Command failed");
-        let mut code = vec![0u8; 2];
-        match port.read(code.as_mut_slice()) {
-            Ok(n) => {
-                let err_code = u16::from_le_bytes([code[0], code[1]]);
-                let msg = code_to_msg(err_code);
-                return Err(format!("Command error {err_code:04x} ({msg})"));
-            }
-            Err(e) => return Err(format!("Error reading error code: {e}")),
-        };
+        let mut code = [0u8; 2];
+        read_full(port, &mut code, deadline)?;
+        let e = RomError::from_code(u16::from_le_bytes(code));
+        return Err(ProtocolError::CommandFailed(e));
     }
-    if stat != OK {
-        return Err(format!(
-            "Unexpected status: {stat:02x?} (wanted OK / {OK:02x?})"
-        ));
+    if &stat != OK {
+        return Err(ProtocolError::UnexpectedStatus(stat));
     }
     debug!("Command OK");
     Ok(())
 }
 
-fn send_cmd(port: &mut Port, command: Command, data: &[u8]) {
-    let cmd = CommandPacket {
-        command: command as u8,
-        size: data.len() as u16,
-    }
-    .to_slice();
+fn send_cmd(port: &mut Port, command: Command, data: &[u8]) -> Result<()> {
+    let cmd = CommandPacket::new(command, data).to_slice();
     debug!("Command: {cmd:02x?}, data: {data:02x?}");
     // First, send the command and data.
-    match port.write(&cmd) {
-        Ok(n) => debug!("Sent command, {n} bytes"),
-        Err(e) => error!("Error sending command: {e}"),
-    }
-    match port.write(data) {
-        Ok(n) => debug!("Sent data, {n} bytes"),
-        Err(e) => error!("Error sending data: {e}"),
-    }
+    port.write_all(&cmd)?;
+    debug!("Sent command, {} bytes", cmd.len());
+    port.write_all(data)?;
+    debug!("Sent data, {} bytes", data.len());
+    Ok(())
 }
 
-fn get_response(port: &mut Port) -> Vec<u8> {
+fn get_response(port: &mut Port) -> Result<Vec<u8>> {
+    let span = debug_span!("get_response", bytes = tracing::field::Empty).entered();
     // First we get the size of the response.
-    let mut size = vec![0u8; 2];
-    match port.read_exact(size.as_mut_slice()) {
-        Ok(_) => debug!("Reponse size read successfully"),
-        Err(e) => panic!("Error reading response size: {e}"),
-    };
-    let size = u16::from_le_bytes([size[0], size[1]]) as usize;
+    let mut size = [0u8; 2];
+    read_full(port, &mut size, response_timeout())?;
+    debug!("Reponse size read successfully");
+    let size = u16::from_le_bytes(size) as usize;
+    span.record("bytes", size);
 
     debug!("Read {size} bytes...");
     let mut resp = vec![0u8; size];
-    match port.read_exact(resp.as_mut_slice()) {
-        Ok(_) => debug!("Reponse data read successfully"),
-        Err(e) => panic!("Error reading response data: {e}"),
-    };
-    resp
+    read_full(port, &mut resp, response_timeout())?;
+    debug!("Reponse data read successfully");
+    Ok(resp)
+}
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// Log commands that change state instead of sending them, for this session.
+/// Queries still go to the device, e.g. for the flash size and eFuse locks.
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed);
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+// Log and skip the command if this is a dry run, as if the ROM said OK.
+fn skip_dry_run(command: Command, data: &[u8]) -> bool {
+    if !is_dry_run() || !command.changes_state() {
+        return false;
+    }
+    let cmd = CommandPacket::new(command, data).to_slice();
+    info!("Dry run, not sending {command:?}: {cmd:02x?}, data: {data:02x?}");
+    true
+}
+
+// Context for everything logged while a command is in flight
+fn command_span(command: Command, data: &[u8]) -> tracing::span::EnteredSpan {
+    debug_span!("send", command = ?command, bytes = data.len()).entered()
 }
 
-fn send(port: &mut Port, command: Command, data: &[u8]) {
-    send_cmd(port, command, data);
-    if let Err(e) = get_ok(port) {
-        panic!("{e}");
+fn send(port: &mut Port, command: Command, data: &[u8]) -> Result<()> {
+    let _span = command_span(command, data);
+    if skip_dry_run(command, data) {
+        return Ok(());
     }
+    send_cmd(port, command, data)?;
+    get_ok_within(port, command_timeout(command, data))
 }
 
-fn send_and_retrieve(port: &mut Port, command: Command, data: &[u8]) -> Vec<u8> {
-    send_cmd(port, command, data);
-    if let Err(e) = get_ok(port) {
-        panic!("{e}");
+fn send_and_retrieve(port: &mut Port, command: Command, data: &[u8]) -> Result<Vec<u8>> {
+    let _span = command_span(command, data);
+    if skip_dry_run(command, data) {
+        return Ok(Vec::new());
     }
+    send_cmd(port, command, data)?;
+    get_ok_within(port, command_timeout(command, data))?;
     get_response(port)
 }
 
-const MAGIC: [u8; 12] = [
+/// Retries consumed during this session, to gauge the link quality
+pub struct SessionStats {
+    pub handshake_retries: AtomicU32,
+    pub flash_write_retries: AtomicU32,
+}
+
+pub static STATS: SessionStats = SessionStats {
+    handshake_retries: AtomicU32::new(0),
+    flash_write_retries: AtomicU32::new(0),
+};
+
+impl SessionStats {
+    pub fn total_retries(&self) -> u32 {
+        let hs = self.handshake_retries.load(Ordering::Relaxed);
+        let fw = self.flash_write_retries.load(Ordering::Relaxed);
+        hs + fw
+    }
+}
+
+impl Display for SessionStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let hs = self.handshake_retries.load(Ordering::Relaxed);
+        let fw = self.flash_write_retries.load(Ordering::Relaxed);
+        write!(f, "Retries: handshake {hs}, flash write {fw}")
+    }
+}
+
+pub(crate) const MAGIC: [u8; 12] = [
     0x50, 0x00, 0x08, 0x00, 0x38, 0xF0, 0x00, 0x20, 0x00, 0x00, 0x00, 0x18,
 ];
 
-const RETRIES: u64 = 5;
+pub(crate) const RETRIES: u64 = 5;
+
+// Upper bound, in case the other side keeps sending
+const DRAIN_MAX_READS: usize = 64;
+
+/// Discard stale bytes left in the buffers, e.g. by a previous aborted run.
+fn drain(port: &mut Port) {
+    if let Err(e) = port.flush() {
+        warn!("Cannot flush port: {e}");
+    }
+    let timeout = port.timeout();
+    if let Err(e) = port.set_timeout(Duration::from_millis(10)) {
+        warn!("Cannot set port timeout: {e}");
+    }
+    let mut buf = [0u8; 256];
+    let mut n = 0;
+    for _ in 0..DRAIN_MAX_READS {
+        match port.read(&mut buf) {
+            Ok(c) if c > 0 => n += c,
+            _ => break,
+        }
+    }
+    if let Err(e) = port.set_timeout(timeout) {
+        warn!("Cannot restore port timeout: {e}");
+    }
+    if n > 0 {
+        debug!("Discarded {n} stale bytes");
+    }
+}
+
+/// Autobaud and magic sequence sent to the mask ROM
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HandshakeParams {
+    /// Number of `U` sent for the auto baud rate detection
+    pub sync_len: usize,
+    /// How often the magic is sent
+    pub magic_repeats: usize,
+    /// Delay between the `U` burst and the magic, in milliseconds
+    pub delay_ms: u64,
+}
+
+impl HandshakeParams {
+    pub const DEFAULT: Self = Self {
+        sync_len: 32,
+        magic_repeats: 1,
+        delay_ms: 100,
+    };
+
+    /// Parse `key = value` lines as written by `to_config`. Missing keys keep
+    /// their default, `#` starts a comment.
+    pub fn from_config(s: &str) -> Result<Self> {
+        let mut p = Self::DEFAULT;
+        for (n, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || ProtocolError::InvalidInput(format!("Line {}: {line}", n + 1));
+            let (k, v) = line.split_once('=').ok_or_else(invalid)?;
+            let v = v.trim();
+            match k.trim() {
+                "sync_len" => p.sync_len = v.parse().map_err(|_| invalid())?,
+                "magic_repeats" => p.magic_repeats = v.parse().map_err(|_| invalid())?,
+                "delay_ms" => p.delay_ms = v.parse().map_err(|_| invalid())?,
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(p)
+    }
+
+    pub fn to_config(&self) -> String {
+        let s = self.sync_len;
+        let r = self.magic_repeats;
+        let d = self.delay_ms;
+        format!(
+            "# Learned by --handshake-learn\nsync_len = {s}\nmagic_repeats = {r}\ndelay_ms = {d}\n"
+        )
+    }
+}
+
+impl Display for HandshakeParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = self.sync_len;
+        let r = self.magic_repeats;
+        let d = self.delay_ms;
+        write!(
+            f,
+            "--handshake-sync-len {s} --handshake-magic-repeats {r} --handshake-delay-ms {d}"
+        )
+    }
+}
+
+/// Load handshake parameters saved by the learn mode, `None` if there are none
+pub fn load_handshake(path: &Path) -> Result<Option<HandshakeParams>> {
+    match std::fs::read_to_string(path) {
+        Ok(s) => HandshakeParams::from_config(&s).map(Some),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn save_handshake(path: &Path, p: &HandshakeParams) -> Result<()> {
+    if let Some(d) = path.parent() {
+        std::fs::create_dir_all(d)?;
+    }
+    std::fs::write(path, p.to_config())?;
+    Ok(())
+}
+
+static HANDSHAKE: OnceLock<HandshakeParams> = OnceLock::new();
+static HANDSHAKE_LEARN: AtomicBool = AtomicBool::new(false);
+static HANDSHAKE_CONFIG: OnceLock<PathBuf> = OnceLock::new();
+
+/// Set the handshake parameters for this session, or search for working ones.
+/// Parameters found by the search are saved to `config` if given.
+pub fn configure_handshake(params: HandshakeParams, learn: bool, config: Option<PathBuf>) {
+    if HANDSHAKE.set(params).is_err() {
+        warn!("Handshake already configured");
+    }
+    HANDSHAKE_LEARN.store(learn, Ordering::Relaxed);
+    if let Some(c) = config {
+        let _ = HANDSHAKE_CONFIG.set(c);
+    }
+}
+
+// Largest chunk size to probe for, and the one in use for this session
+static CHUNK_SIZE_LIMIT: AtomicU32 = AtomicU32::new(CHUNK_SIZE);
+static SESSION_CHUNK_SIZE: AtomicU32 = AtomicU32::new(CHUNK_SIZE);
+static CHUNK_SIZE_PROBED: AtomicBool = AtomicBool::new(false);
+
+/// Allow transfers in chunks of up to `max` bytes for this session. Whether
+/// the ROM accepts them is probed once the flash is set up in `get_flash_id`.
+pub fn configure_chunk_size(max: u32) -> Result<()> {
+    if !(CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&max) || !max.is_multiple_of(CHUNK_SIZE) {
+        return Err(ProtocolError::InvalidInput(format!(
+            "Chunk size {max} must be a multiple of {CHUNK_SIZE} up to {MAX_CHUNK_SIZE}"
+        )));
+    }
+    CHUNK_SIZE_LIMIT.store(max, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Chunk size for flash reads, flash writes and segment data in this session
+pub fn chunk_size() -> u32 {
+    SESSION_CHUNK_SIZE.load(Ordering::Relaxed)
+}
+
+// Only reads are probed, as probing writes would need a scratch sector. When
+// a larger write or segment chunk fails anyway, go back to the default.
+fn fall_back_chunk_size(port: &mut Port, e: &ProtocolError) {
+    let cs = chunk_size();
+    warn!("{cs} byte chunks failed ({e}), fall back to chunk size {CHUNK_SIZE}");
+    drain(port);
+    SESSION_CHUNK_SIZE.store(CHUNK_SIZE, Ordering::Relaxed);
+}
+
+// The ROM does not report its buffer size, so read a large chunk once and keep
+// the default if it is rejected.
+fn probe_chunk_size(port: &mut Port) {
+    let max = CHUNK_SIZE_LIMIT.load(Ordering::Relaxed);
+    if max <= CHUNK_SIZE || CHUNK_SIZE_PROBED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    match read_flash_chunk(port, 0, max) {
+        Ok(d) if d.len() == max as usize => {
+            info!("Use chunk size {max}");
+            SESSION_CHUNK_SIZE.store(max, Ordering::Relaxed);
+        }
+        Ok(d) => {
+            let l = d.len();
+            warn!("Got {l} bytes for a {max} byte read, keep chunk size {CHUNK_SIZE}");
+        }
+        Err(e) => {
+            warn!("{max} byte chunks not accepted ({e}), keep chunk size {CHUNK_SIZE}");
+            drain(port);
+        }
+    }
+}
+
+// Bytes to skip looking for the handshake OK, e.g. echoes of the `U` burst or
+// noise from the ROM's own auto baud rate detection
+pub(crate) const HANDSHAKE_SCAN_WINDOW: usize = 32;
+
+// Look for the OK within the first bytes instead of expecting it right away.
+fn scan_for_ok(port: &mut Port, deadline: Duration) -> Result<()> {
+    let end = Instant::now() + deadline;
+    let mut last = [0u8; 2];
+    for n in 1..=HANDSHAKE_SCAN_WINDOW {
+        let mut b = [0u8; 1];
+        let left = end.saturating_duration_since(Instant::now());
+        read_full(port, &mut b, left)?;
+        last = [last[1], b[0]];
+        if n >= 2 && &last == OK {
+            if n > 2 {
+                debug!("Skipped {} bytes before OK", n - 2);
+            }
+            return Ok(());
+        }
+    }
+    Err(ProtocolError::UnexpectedStatus(last))
+}
+
+// Candidates for the learn mode, tried in order
+const LEARN_SYNC_LENS: [usize; 5] = [32, 16, 64, 8, 128];
+const LEARN_DELAYS_MS: [u64; 4] = [100, 50, 200, 20];
+const LEARN_MAGIC_REPEATS: [usize; 2] = [1, 2];
+
+fn try_handshake(port: &mut Port, p: &HandshakeParams) -> Result<()> {
+    // Leftovers from a previous attempt would be taken for the response.
+    if let Err(e) = port.clear(serialport::ClearBuffer::Input) {
+        warn!("Cannot clear input buffer: {e}");
+    }
+    port.write_all(&vec![b'U'; p.sync_len])?;
+    debug!("Wrote UU...: {} bytes", p.sync_len);
+    // Give the auto baud rate detection + adjustment some time.
+    sleep(Duration::from_millis(p.delay_ms));
+    for _ in 0..p.magic_repeats {
+        port.write_all(&MAGIC)?;
+        debug!("Wrote magic: {} bytes", MAGIC.len());
+    }
+    scan_for_ok(port, HALF_SEC)?;
+    if p.magic_repeats > 1 {
+        // Responses to the extra magic
+        drain(port);
+    }
+    Ok(())
+}
+
+/// Try all combinations of handshake parameters, returning the first to work.
+pub fn learn_handshake(port: &mut Port) -> Option<HandshakeParams> {
+    for sync_len in LEARN_SYNC_LENS {
+        for delay_ms in LEARN_DELAYS_MS {
+            for magic_repeats in LEARN_MAGIC_REPEATS {
+                let p = HandshakeParams {
+                    sync_len,
+                    magic_repeats,
+                    delay_ms,
+                };
+                info!("Try {p}");
+                drain(port);
+                match try_handshake(port, &p) {
+                    Ok(()) => return Some(p),
+                    Err(e) => debug!("{e}"),
+                }
+                // Let the ROM give up on a partial sequence.
+                sleep(HALF_SEC);
+            }
+        }
+    }
+    None
+}
 
-pub fn handshake(port: &mut Port) {
+pub fn handshake(port: &mut Port) -> Result<()> {
+    let _span = info_span!("handshake").entered();
     debug!("Handshake");
+    drain(port);
+    if HANDSHAKE_LEARN.load(Ordering::Relaxed) {
+        let Some(p) = learn_handshake(port) else {
+            error!("No handshake parameters worked");
+            return Err(ProtocolError::Timeout);
+        };
+        match HANDSHAKE_CONFIG.get() {
+            Some(c) => {
+                save_handshake(c, &p)?;
+                info!(
+                    "Handshake succeeded, saved the parameters to {}",
+                    c.display()
+                );
+            }
+            None => info!("Handshake succeeded, pass these options to use the parameters:\n{p}"),
+        }
+        return Ok(());
+    }
+    let p = HANDSHAKE.get().unwrap_or(&HandshakeParams::DEFAULT);
+    let mut last = ProtocolError::Timeout;
     for r in 0..RETRIES {
-        let written = port.write(&[b'U'; 32]);
-        debug!("Wrote UU...: {written:?} bytes");
-        // Give the auto baud rate detection + adjustment some time.
-        sleep(Duration::from_millis(100));
-        let written = port.write(&MAGIC);
-        debug!("Wrote magic: {written:?} bytes");
-        match get_ok(port) {
+        match try_handshake(port, p) {
             Ok(()) => {
                 debug!("Status okay, now send command");
-                return;
+                return Ok(());
             }
             Err(e) => {
                 error!("{e}, retry...");
+                STATS.handshake_retries.fetch_add(1, Ordering::Relaxed);
                 sleep(Duration::from_millis(r * 200));
+                last = e;
             }
         }
     }
     error!("Tried handshake {RETRIES} times, to no avail. :(");
-    panic!("Failed to connect");
+    Err(last)
+}
+
+// Time for both sides to switch the UART clock
+const CHANGE_RATE_DELAY: Duration = Duration::from_millis(50);
+
+/// Switch both the ROM and the port to `baud` mid-session, e.g. to do the
+/// handshake at a safe rate and bulk transfers fast. The ROM acknowledges at
+/// the old rate, then a round trip at the new one confirms the switch, falling
+/// back to a new handshake.
+pub fn change_rate(port: &mut Port, baud: u32) -> Result<()> {
+    check_baud(baud)?;
+    let old = port.baud_rate()?;
+    info!("Change baud rate from {old} to {baud}");
+    let d = [old.to_le_bytes(), baud.to_le_bytes()].concat();
+    send(port, Command::ChangeRate, &d)?;
+    port.set_baud_rate(baud)?;
+    sleep(CHANGE_RATE_DELAY);
+    drain(port);
+    if let Err(e) = get_boot_info(port) {
+        warn!("No response at {baud} baud ({e}), resynchronize");
+        handshake(port)?;
+    }
+    Ok(())
 }
 
 #[derive(Clone, Debug, Copy, FromBytes, IntoBytes)]
@@ -255,8 +1078,7 @@ impl Display for BootInfo {
         let cfg0 = self.sw_config0;
         let cfg1 = self.sw_config1;
         let macx = self.wifi_mac_x;
-        let mac = macx.mac_addr();
-        let mac = format!("Wi-Fi MAC: {mac:012x}");
+        let mac = format!("Wi-Fi MAC: {}", macx.mac());
         let info = macx.info();
 
         write!(
@@ -266,20 +1088,80 @@ impl Display for BootInfo {
     }
 }
 
+/// Raw chip identifier as returned by `GetChipId`, for which there is no
+/// documented layout
+pub fn get_chip_id(port: &mut Port) -> Result<Vec<u8>> {
+    debug!("Get chip ID");
+    send_and_retrieve(port, Command::GetChipId, &[])
+}
+
+/// Log the chip ID next to the ROM driver version and the eFuse package info,
+/// to tell silicon revisions apart.
+pub fn show_chip_id(port: &mut Port) -> Result<()> {
+    match get_chip_id(port) {
+        Ok(id) => info!("Chip ID: {id:02x?}"),
+        Err(e) => error!("Chip ID: {e}"),
+    }
+    let bi = get_boot_info(port)?;
+    let rom_drv = bi.rom_driver_version;
+    info!("ROM driver version: {rom_drv:08x}");
+    // The boot info drops bits of the info word, so take it from the fuses.
+    let ef = read_efuse_range(port, 0, EFUSE_SLOT_SIZE)?;
+    if let Ok(b) = EfuseBlock0::read_from_bytes(&ef) {
+        let macx = b.wifi_mac_x;
+        info!("eFuse {}", macx.info());
+    }
+    Ok(())
+}
+
 // TODO: other fields, support non-BL808 chips
-fn get_boot_info(port: &mut Port) -> BootInfo {
+fn get_boot_info(port: &mut Port) -> Result<BootInfo> {
     debug!("Get boot info");
-    let mut res = send_and_retrieve(port, Command::GetBootInfo, &[]);
+    let res = send_and_retrieve(port, Command::GetBootInfo, &[])?;
     debug!("{res:02x?}");
 
-    let bi = BootInfo::read_from_bytes(&res).unwrap();
-    bi
+    BootInfo::read_from_bytes(&res).map_err(|_| {
+        ProtocolError::InvalidResponse(format!("Unexpected boot info size: {}", res.len()))
+    })
+}
+
+/// Size of a NIST P-256 public key as the ROM exchanges it: x, then y, 32
+/// bytes each, without the SEC1 `04` prefix
+pub const ECDH_PUBLIC_KEY_SIZE: usize = 64;
+
+/// Start the ECDH key exchange that ROMs provisioned for a secure loader
+/// session expect before other commands: send the host's ephemeral P-256
+/// public key and get the device's.
+///
+/// There is no P-256 implementation in this crate, so generating the keypair
+/// and deriving the shared secret are up to the caller, see `ecdh_challenge`.
+pub fn ecdh_get_pk(
+    port: &mut Port,
+    host_key: &[u8; ECDH_PUBLIC_KEY_SIZE],
+) -> Result<[u8; ECDH_PUBLIC_KEY_SIZE]> {
+    debug!("Get ECDH public key");
+    let res = send_and_retrieve(port, Command::EcdhGetPk, host_key)?;
+    match res.get(..ECDH_PUBLIC_KEY_SIZE) {
+        Some(k) => Ok(k.try_into().unwrap()),
+        None => Err(ProtocolError::InvalidResponse(format!(
+            "Unexpected ECDH public key size: {}",
+            res.len()
+        ))),
+    }
+}
+
+/// Send `EcdhChallenge` with `data`, empty to ask for the challenge, and
+/// return the ROM's response. Answering it requires the shared secret from
+/// the exchange in `ecdh_get_pk`.
+pub fn ecdh_challenge(port: &mut Port, data: &[u8]) -> Result<Vec<u8>> {
+    debug!("ECDH challenge");
+    send_and_retrieve(port, Command::EcdhChallenge, data)
 }
 
 // NOTE: values hardcoded from vendor config;
 // TODO: define struct for variants
 // `chips/bl808/eflash_loader/eflash_loader_cfg.conf` section [FLASH_CFG]
-fn init_flash(port: &mut Port, bi: &BootInfo) {
+pub(crate) fn flash_params(bi: &BootInfo) -> [u8; 4] {
     // IO mode
     //   0: NIO,
     //   1: DO,
@@ -306,180 +1188,1443 @@ fn init_flash(port: &mut Port, bi: &BootInfo) {
     let flash_clock_delay = 0;
 
     let sw_cfg0 = bi.sw_config0;
-    let data = [
+    [
         sw_cfg0.spi_flash_pin_cfg() as u8,
         flash_io_mode,
         flash_clock_cfg,
         flash_clock_delay,
-    ];
-    send(port, Command::FlashSetParam, &data)
+    ]
 }
 
-pub fn get_flash_id(port: &mut Port) {
-    let bi = get_boot_info(port);
-    init_flash(port, &bi);
-
-    info!("Get JEDEC flash manufacturer/device ID");
-    let res = send_and_retrieve(port, Command::FlashReadJedecId, &[]);
-    let m = res[0];
-    // https://github.com/SourceArcade/flashprog/blob/main/include/flashchips.h
-    let manuf = match m {
-        0xef => "Winbond",
-        0xc8 => "GigaDevice",
-        _ => "unknown",
-    };
-    // TODO: match manufacturer first; is there a library?
-    let device = u16::from_le_bytes([res[1], res[2]]);
-    info!("Manufacturer: {manuf} ({m:02x}), device: {device:04x}");
+fn init_flash(port: &mut Port, bi: &BootInfo) -> Result<()> {
+    send(port, Command::FlashSetParam, &flash_params(bi))
 }
 
-const STEP_SIZE: usize = 32;
+/// Pass a full flash config, e.g. from `boot::flash_config_with_erase`, for
+/// the ROM to use instead of its built-in one.
+pub fn set_flash_config(port: &mut Port, config: &[u8]) -> Result<()> {
+    let bi = get_boot_info(port)?;
+    debug!("Set flash config: {config:02x?}");
+    let d = [flash_params(&bi).as_slice(), config].concat();
+    send(port, Command::FlashSetParam, &d)
+}
 
-fn get_flash_sha(port: &mut Port, bi: &BootInfo) {
-    debug!("Read flash SHA");
+// Flash size from the JEDEC ID, which also sets up the flash
+fn get_flash_size(port: &mut Port) -> Result<u32> {
+    let id = get_flash_id(port)?;
+    id.size().map_err(ProtocolError::InvalidResponse)
+}
 
-    let a = 0x00u32;
-    let l = 0x10u32;
-    let d = [a.to_le_bytes(), l.to_le_bytes()].concat();
+pub fn get_flash_id(port: &mut Port) -> Result<FlashInfo> {
+    let bi = get_boot_info(port)?;
+    init_flash(port, &bi)?;
 
-    init_flash(port, bi);
-    let res = send_and_retrieve(port, Command::FlashReadSha, &d);
-    for o in (0..res.len()).step_by(STEP_SIZE) {
-        debug!("{:08x}: {:02x?}", a as usize + o, &res[o..o + STEP_SIZE]);
+    info!("Get JEDEC flash manufacturer/device ID");
+    let res = send_and_retrieve(port, Command::FlashReadJedecId, &[])?;
+    if res.len() < 3 {
+        return Err(ProtocolError::InvalidResponse(format!(
+            "Unexpected JEDEC ID response: {res:02x?}"
+        )));
     }
+    // https://github.com/SourceArcade/flashprog/blob/main/include/flashchips.h
+    let id = FlashInfo::from_jedec([res[0], res[1], res[2]]);
+    info!("{id}");
+    probe_chunk_size(port);
+    Ok(id)
 }
 
-const EFUSE_SLOT_SIZE: u32 = 0x80;
+pub fn read_status_reg(port: &mut Port, command: u8, len: u32) -> Result<Vec<u8>> {
+    debug!("Read flash status register, command {command:02x}");
+    let d = [(command as u32).to_le_bytes(), len.to_le_bytes()].concat();
+    send_and_retrieve(port, Command::FlashReadStatusReg, &d)
+}
 
-// NOTE: The vendor code apparently accesses 3 slots, but I could only read 2.
-pub fn get_efuses(port: &mut Port) -> Vec<u8> {
-    debug!("Read efuses");
+/// Write `value` to the status register(s) starting at the one `command`
+/// addresses, e.g. SR1 and SR2 via `flash::WRITE_STATUS_REG`.
+pub fn write_status_reg(port: &mut Port, command: u8, value: &[u8]) -> Result<()> {
+    debug!("Write flash status register, command {command:02x}: {value:02x?}");
+    if value.len() > 4 {
+        return Err(ProtocolError::InvalidInput(format!(
+            "Status register value of {} bytes is too long",
+            value.len()
+        )));
+    }
+    let mut v = [0u8; 4];
+    v[..value.len()].copy_from_slice(value);
+    let len = value.len() as u32;
+    let d = [(command as u32).to_le_bytes(), len.to_le_bytes(), v].concat();
+    send(port, Command::FlashWriteStatusReg, &d)
+}
+
+// SR1 and SR2, combined as in `flash::Status`
+fn read_status(port: &mut Port) -> Result<u16> {
+    let sr = |port: &mut Port, c| match read_status_reg(port, c, 1)?.first() {
+        Some(&b) => Ok(b),
+        None => Err(ProtocolError::InvalidResponse(
+            "Empty status register response".to_string(),
+        )),
+    };
+    let sr1 = sr(port, flash::READ_STATUS_REG1)?;
+    let sr2 = sr(port, flash::READ_STATUS_REG2)?;
+    Ok(u16::from_le_bytes([sr1, sr2]))
+}
+
+fn flash_status(port: &mut Port) -> Result<flash::Status> {
+    let id = get_flash_id(port)?;
+    let Some(layout) = flash::status_layout(id.manufacturer) else {
+        return Err(ProtocolError::InvalidInput(format!(
+            "No status register layout known for manufacturer {:02x}",
+            id.manufacturer
+        )));
+    };
+    Ok(flash::Status {
+        layout,
+        bits: read_status(port)?,
+        size: id.size().map_err(ProtocolError::InvalidResponse)?,
+    })
+}
+
+pub fn get_flash_protect(port: &mut Port) -> Result<()> {
+    let status = flash_status(port)?;
+    info!("{status}");
+    Ok(())
+}
+
+/// Set or clear the quad enable bit, which quad I/O reads need and which also
+/// turns the WP# and HOLD# pins into data lines. The bit is non-volatile.
+pub fn set_flash_qe(port: &mut Port, enable: bool) -> Result<()> {
+    let status = flash_status(port)?;
+    if status.qe() == enable {
+        info!("QE is already {enable}");
+        return Ok(());
+    }
+    let qe = 1u16 << status.layout.qe;
+    let bits = if enable {
+        status.bits | qe
+    } else {
+        status.bits & !qe
+    };
+    info!("Write status registers {bits:04x}");
+    write_status_reg(port, flash::WRITE_STATUS_REG, &bits.to_le_bytes())?;
+    if is_dry_run() {
+        return Ok(());
+    }
+    let got = read_status(port)?;
+    if got & qe != bits & qe {
+        return Err(ProtocolError::VerifyFailed(format!(
+            "status registers read back as {got:04x}, wanted QE {enable}"
+        )));
+    }
+    info!("QE is now {enable}");
+    Ok(())
+}
+
+const STEP_SIZE: usize = 32;
+
+fn get_flash_sha(port: &mut Port, bi: &BootInfo) -> Result<()> {
+    debug!("Read flash SHA");
+    init_flash(port, bi)?;
+    let sha = flash_sha(port, 0, 0x10)?;
+    debug!("00000000: {sha:02x?}");
+    Ok(())
+}
+
+/// SHA256 of a flash region as computed by the mask ROM. Expects the flash to
+/// be set up, e.g. via `get_flash_id`.
+pub fn flash_sha(port: &mut Port, offset: u32, size: u32) -> Result<[u8; 32]> {
+    let d = [offset.to_le_bytes(), size.to_le_bytes()].concat();
+    let res = send_and_retrieve(port, Command::FlashReadSha, &d)?;
+    res.try_into().map_err(|r| {
+        ProtocolError::InvalidResponse(format!("Unexpected flash SHA256 response: {r:02x?}"))
+    })
+}
+
+/// Read flash through the XIP (execute in place) controller, as the CPU sees
+/// it when running from flash, and check it against the ROM's XIP SHA256.
+/// XIP mode is left again even if a read fails.
+pub fn xip_dump(
+    port: &mut Port,
+    offset: u32,
+    size: u32,
+    file: &str,
+    progress: Progress,
+) -> Result<()> {
+    get_flash_id(port)?;
+    let mut f = File::create(file)?;
+    info!("Dump {size:08x} bytes via XIP @ {offset:08x}");
+    send(port, Command::FlashXipReadStart, &[])?;
+    let res = xip_read(port, offset, size, &mut f, progress);
+    let finish = send(port, Command::FlashXipReadFinish, &[]);
+    res?;
+    finish
+}
+
+fn xip_read(
+    port: &mut Port,
+    offset: u32,
+    size: u32,
+    f: &mut File,
+    progress: Progress,
+) -> Result<()> {
+    let mut hash = Sha256::new();
+    let start = Instant::now();
+    let end = offset + size;
+    let cs = chunk_size();
+    for a in (offset..end).step_by(cs as usize) {
+        let l = cs.min(end - a);
+        let d = [a.to_le_bytes(), l.to_le_bytes()].concat();
+        let res = send_and_retrieve(port, Command::FlashXipRead, &d)?;
+        if res.len() != l as usize {
+            let n = res.len();
+            return Err(ProtocolError::InvalidResponse(format!(
+                "Got {n} bytes for a {l} byte XIP read"
+            )));
+        }
+        hash.update(&res);
+        f.write_all(&res)?;
+        progress(a + l - offset, size);
+    }
+    report_throughput(port, "Read (XIP)", size as usize, start.elapsed());
+    let sha: [u8; 32] = hash.finalize().into();
+    let d = [offset.to_le_bytes(), size.to_le_bytes()].concat();
+    let xip_sha = send_and_retrieve(port, Command::FlashXipReadSha, &d)?;
+    if xip_sha != sha {
+        return Err(ProtocolError::VerifyFailed(format!(
+            "XIP SHA256 {xip_sha:02x?}, read SHA256 {sha:02x?}"
+        )));
+    }
+    info!("SHA256 verified: {sha:02x?}");
+    Ok(())
+}
+
+/// Compare flash at `offset` with `data`, via the ROM's SHA256 first and, if
+/// that differs, by reading it back to report the first differing offset.
+pub fn verify_flash(port: &mut Port, offset: u32, data: &[u8], progress: Progress) -> Result<()> {
+    get_flash_id(port)?;
+    let size = data.len() as u32;
+    info!("Verify {size:08x} bytes @ {offset:08x}");
+    let sha: [u8; 32] = Sha256::digest(data).into();
+    if flash_sha(port, offset, size)? == sha {
+        info!("SHA256 matches: {sha:02x?}");
+        return Ok(());
+    }
+    warn!("SHA256 differs, read back to locate the mismatch");
+    let cs = chunk_size() as usize;
+    let mut first = None;
+    let mut count = 0;
+    for (c, expected) in data.chunks(cs).enumerate() {
+        let o = c * cs;
+        let a = offset + o as u32;
+        let got = read_flash_chunk(port, a, expected.len() as u32)?;
+        for (i, (g, e)) in got.iter().zip(expected).enumerate() {
+            if g != e {
+                first.get_or_insert((a as usize + i, *g, *e));
+                count += 1;
+            }
+        }
+        progress((o + expected.len()) as u32, size);
+    }
+    match first {
+        Some((a, g, e)) => Err(ProtocolError::VerifyFailed(format!(
+            "{count} byte(s) differ, first @ {a:08x}: flash {g:02x}, file {e:02x}"
+        ))),
+        // The SHA256 may have been off due to a transfer error.
+        None => {
+            warn!("Read back data matches despite the different SHA256");
+            Ok(())
+        }
+    }
+}
+
+const EFUSE_SLOT_SIZE: u32 = 0x80;
+
+// NOTE: The vendor code apparently accesses 3 slots, but I could only read 2.
+pub fn get_efuses(port: &mut Port) -> Result<Vec<u8>> {
+    debug!("Read efuses");
 
     let mut ret = Vec::<u8>::new();
     let a = 0u32;
     let size = EFUSE_SLOT_SIZE.to_le_bytes();
     let d = [a.to_le_bytes(), size].concat();
-    let res = send_and_retrieve(port, Command::EfuseRead, &d);
+    let res = send_and_retrieve(port, Command::EfuseRead, &d)?;
     ret.extend_from_slice(&res);
-    for o in (0..res.len()).step_by(STEP_SIZE) {
-        debug!("{:08x}: {:02x?}", a as usize + o, &res[o..o + STEP_SIZE]);
+    for (i, c) in res.chunks(STEP_SIZE).enumerate() {
+        debug!("{:08x}: {c:02x?}", a as usize + i * STEP_SIZE);
     }
     match EfuseBlock0::read_from_bytes(&res) {
         Ok(f) => info!("eFuse block 0:\n{f}"),
-        Err(e) => error!("Could not parse eFuse data"),
+        Err(_) => error!("Could not parse eFuse data, got {} bytes", res.len()),
     }
     let a = EFUSE_SLOT_SIZE;
     let d = [a.to_le_bytes(), size].concat();
-    let res = send_and_retrieve(port, Command::EfuseRead, &d);
+    let res = send_and_retrieve(port, Command::EfuseRead, &d)?;
     ret.extend_from_slice(&res);
-    for o in (0..res.len()).step_by(STEP_SIZE) {
-        debug!("{:08x}: {:02x?}", a as usize + o, &res[o..o + STEP_SIZE]);
+    for (i, c) in res.chunks(STEP_SIZE).enumerate() {
+        debug!("{:08x}: {c:02x?}", a as usize + i * STEP_SIZE);
     }
     match EfuseBlock1::read_from_bytes(&res) {
         Ok(f) => info!("eFuse block 1:\n{f}"),
-        Err(e) => error!("Could not parse eFuse data"),
+        Err(_) => error!("Could not parse eFuse data, got {} bytes", res.len()),
+    }
+
+    Ok(ret)
+}
+
+// NOTE: The vendor code accesses 3 slots, see `get_efuses`.
+const EFUSE_SIZE: u32 = 3 * EFUSE_SLOT_SIZE;
+
+/// Read an arbitrary word-aligned eFuse range, split at slot boundaries.
+pub fn read_efuse_range(port: &mut Port, base: u32, count: u32) -> Result<Vec<u8>> {
+    let end = base.saturating_add(count);
+    if count == 0 || end > EFUSE_SIZE {
+        return Err(ProtocolError::InvalidInput(format!(
+            "Range {count:#x} @ {base:#x} is empty or exceeds eFuse size {EFUSE_SIZE:#x}"
+        )));
+    }
+    if !base.is_multiple_of(4) || !count.is_multiple_of(4) {
+        let e = "eFuse range must be 4-byte aligned".to_string();
+        return Err(ProtocolError::InvalidInput(e));
+    }
+    let mut ret = Vec::<u8>::new();
+    let mut a = base;
+    while a < end {
+        let l = end.min((a / EFUSE_SLOT_SIZE + 1) * EFUSE_SLOT_SIZE) - a;
+        let d = [a.to_le_bytes(), l.to_le_bytes()].concat();
+        let res = send_and_retrieve(port, Command::EfuseRead, &d)?;
+        for (i, c) in res.chunks(STEP_SIZE).enumerate() {
+            debug!("{:08x}: {c:02x?}", a as usize + i * STEP_SIZE);
+        }
+        ret.extend_from_slice(&res);
+        a += l;
     }
+    Ok(ret)
+}
 
-    ret
+pub fn read_efuse_block0(port: &mut Port) -> Result<EfuseBlock0> {
+    let d = [0u32.to_le_bytes(), EFUSE_SLOT_SIZE.to_le_bytes()].concat();
+    let res = send_and_retrieve(port, Command::EfuseRead, &d)?;
+    EfuseBlock0::read_from_bytes(&res).map_err(|_| {
+        ProtocolError::InvalidResponse(format!("Unexpected eFuse block 0 size: {}", res.len()))
+    })
 }
 
-pub fn reset(port: &mut Port) {
+pub fn jtag(port: &mut Port, set: Option<u8>, confirm: bool) -> Result<()> {
+    let f = read_efuse_block0(port)?;
+    let cfg = f.config;
+    let j0 = cfg.debug_jtag_0_dis();
+    let j1 = cfg.debug_jtag_1_dis();
+    let crd = cfg.cpu_reset_debug_dis();
+    let sw_cfg = f.sw_config;
+    let cur = sw_cfg.sw_config0.jtag_cfg();
+    let pins = efuses::jtag_pins(cur);
+    info!("JTAG config: {cur} ({pins})");
+    info!("Debug JTAG 0 disable: {j0}, JTAG 1 disable: {j1}, CPU reset debug disable: {crd}");
+
+    let Some(v) = set else {
+        return Ok(());
+    };
+    let invalid = |e: String| Err(ProtocolError::InvalidInput(e));
+    if v > 3 {
+        return invalid(format!("Invalid JTAG config {v}, must be 0-3"));
+    }
+    if v == cur {
+        info!("JTAG config is already {v}");
+        return Ok(());
+    }
+    // eFuse bits can only be set, never cleared.
+    if v & cur != cur {
+        return invalid(format!(
+            "Cannot change JTAG config from {cur} to {v}, that would clear bits"
+        ));
+    }
+    if v >= 2 {
+        warn!("Disabling JTAG is effectively permanent!");
+        if !confirm {
+            return invalid("Not disabling JTAG without confirmation".to_string());
+        }
+    }
+    let w = SwConfig0::new().with_jtag_cfg(v).into_bits();
+    set_efuse(port, efuses::SW_CONFIG0_OFFSET, w)?;
+    info!("JTAG config now: {v} ({})", efuses::jtag_pins(v));
+    Ok(())
+}
+
+pub fn get_debug_password(port: &mut Port) -> Result<()> {
+    let f = read_efuse_block0(port)?;
+    let lock = f.lock;
+    let rd = lock.rd_lock_debug_password();
+    let wr = lock.wr_lock_debug_password();
+    let pw1 = f.debug_password1;
+    let pw2 = f.debug_password2;
+    let set = match (rd, pw1 | pw2) {
+        (true, _) => "unknown (read-locked)",
+        (false, 0) => "no",
+        (false, _) => "yes",
+    };
+    info!("Debug password set: {set}\nRead lock: {rd}, write lock: {wr}");
+    Ok(())
+}
+
+pub fn set_debug_password(
+    port: &mut Port,
+    password: [u8; 16],
+    lock: bool,
+    confirm: bool,
+) -> Result<()> {
+    let f = read_efuse_block0(port)?;
+    let l = f.lock;
+    let invalid = |e: &str| Err(ProtocolError::InvalidInput(e.to_string()));
+    if l.wr_lock_debug_password() {
+        return invalid("Debug password is write-locked");
+    }
+    if l.rd_lock_debug_password() {
+        warn!("Debug password is read-locked, cannot check the current value");
+    } else {
+        let pw1 = f.debug_password1;
+        let pw2 = f.debug_password2;
+        let cur = [pw1.to_le_bytes(), pw2.to_le_bytes()].concat();
+        // eFuse bits can only be set, never cleared.
+        if cur.iter().zip(password).any(|(c, p)| c & !p != 0) {
+            return invalid("A different debug password is already set");
+        }
+    }
+    warn!("Burning the debug password is permanent!");
+    if !confirm {
+        return invalid("Not burning the debug password without confirmation");
+    }
+    set_efuses(port, efuses::DEBUG_PASSWORD_OFFSET, &password, false)?;
+    if lock {
+        let w = Data0Lock::new()
+            .with_wr_lock_debug_password(true)
+            .with_rd_lock_debug_password(true)
+            .into_bits();
+        set_efuse(port, efuses::DATA0_LOCK_OFFSET, w)?;
+    }
+    info!("Debug password burned");
+    Ok(())
+}
+
+pub fn reset(port: &mut Port) -> Result<()> {
     debug!("Reset");
-    send(port, Command::Reset, &[]);
+    send(port, Command::Reset, &[])
+}
+
+/// Write-locked eFuse regions, from the lock words of both blocks
+pub fn read_write_locks(port: &mut Port) -> Result<Vec<(&'static str, std::ops::Range<u32>)>> {
+    let word = |port: &mut Port, a| -> Result<u32> {
+        let r = read_efuse_range(port, a, 4)?;
+        match r.as_slice().try_into() {
+            Ok(w) => Ok(u32::from_le_bytes(w)),
+            Err(_) => Err(ProtocolError::InvalidResponse(format!(
+                "Cannot read eFuse lock word @ {a:#04x}: {r:02x?}"
+            ))),
+        }
+    };
+    let lock0 = Data0Lock::from_bits(word(port, efuses::DATA0_LOCK_OFFSET)?);
+    let a1 = EFUSE_SLOT_SIZE + std::mem::offset_of!(EfuseBlock1, lock) as u32;
+    let lock1 = efuses::Data1Lock::from_bits(word(port, a1)?);
+    Ok(efuses::write_locked(lock0, lock1))
 }
 
-pub fn set_efuses(port: &mut Port, address: u32, data: &[u8]) {
+/// Program eFuses one word at a time, reading back each word before going on,
+/// so that a failure leaves as few words as possible programmed. Note that
+/// read-locked words read back as zero and hence fail verification. Words in
+/// write-locked regions are refused unless `force` is set.
+pub fn set_efuses(port: &mut Port, address: u32, data: &[u8], force: bool) -> Result<()> {
     debug!("Write efuses @ {address:08x}: {data:02x?}");
-    let mut d = Vec::<u8>::new();
-    d.extend_from_slice(&address.to_le_bytes());
-    d.extend_from_slice(data);
-    send(port, Command::EfuseWrite, &d);
+    if !address.is_multiple_of(4) || !data.len().is_multiple_of(4) {
+        let e = "eFuse writes must be 4-byte aligned".to_string();
+        return Err(ProtocolError::InvalidInput(e));
+    }
+    let locks = read_write_locks(port)?;
+    for (i, w) in data.chunks_exact(4).enumerate() {
+        let a = address + 4 * i as u32;
+        if w == [0; 4] {
+            continue;
+        }
+        if let Some((n, r)) = locks.iter().find(|(_, r)| r.contains(&a)) {
+            let (s, e) = (r.start, r.end - 1);
+            let e = format!("eFuse word @ {a:#04x} is in {s:#04x}-{e:#04x}, locked by {n}");
+            if !force {
+                return Err(ProtocolError::InvalidInput(e));
+            }
+            warn!("{e}, trying anyway");
+        }
+    }
+    for (i, w) in data.chunks_exact(4).enumerate() {
+        let a = address + 4 * i as u32;
+        let v = u32::from_le_bytes(w.try_into().unwrap());
+        // Bits can only be set, so there is nothing to program.
+        if v == 0 {
+            continue;
+        }
+        debug!("Write eFuse word @ {a:#04x}: {v:08x}");
+        let d = [a.to_le_bytes(), v.to_le_bytes()].concat();
+        send(port, Command::EfuseWrite, &d)?;
+        if is_dry_run() {
+            continue;
+        }
+        let r = read_efuse_range(port, a, 4)?;
+        let Ok(got) = r.as_slice().try_into().map(u32::from_le_bytes) else {
+            return Err(ProtocolError::InvalidResponse(format!(
+                "Cannot read back eFuse word @ {a:#04x}: {r:02x?}"
+            )));
+        };
+        if got & v != v {
+            return Err(ProtocolError::VerifyFailed(format!(
+                "eFuse word @ {a:#04x} failed, wrote {v:08x} got {got:08x}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+pub fn set_efuse(port: &mut Port, address: u32, value: u32) -> Result<()> {
+    set_efuses(port, address, &value.to_le_bytes(), false)
 }
 
-pub fn set_efuse(port: &mut Port, address: u32, value: u32) {
-    set_efuses(port, address, &value.to_le_bytes());
+// The ROM appends a CRC32 over the MAC address, and expects one on writes.
+const MAC_CRC_SIZE: usize = 4;
+
+/// Read the Wi-Fi MAC address via the dedicated ROM command.
+pub fn read_mac(port: &mut Port) -> Result<MacAddr> {
+    let res = send_and_retrieve(port, Command::EfuseReadMac, &[])?;
+    let Some(mac) = res.first_chunk::<6>() else {
+        return Err(ProtocolError::InvalidResponse(format!(
+            "Unexpected MAC address response: {res:02x?}"
+        )));
+    };
+    if let Some(c) = res[6..].first_chunk::<MAC_CRC_SIZE>() {
+        let crc = u32::from_le_bytes(*c);
+        let expected = CRC32.checksum(mac);
+        if crc != expected {
+            return Err(ProtocolError::InvalidResponse(format!(
+                "MAC address CRC {crc:08x}, expected {expected:08x}"
+            )));
+        }
+    }
+    Ok(MacAddr(*mac))
+}
+
+/// Burn the Wi-Fi MAC address via the dedicated ROM command and read it back.
+/// Addresses outside of Bouffalo Lab's OUI are refused unless `force` is set.
+/// Like all eFuses, bits can only be set, so this generally works only once.
+pub fn write_mac(port: &mut Port, mac: MacAddr, force: bool) -> Result<()> {
+    if !mac.is_bouffalo() {
+        let oui = efuses::BOUFFALO_OUI.map(|b| format!("{b:02x}")).join(":");
+        let e = format!("MAC address {mac} is not in the Bouffalo Lab range {oui}");
+        if !force {
+            return Err(ProtocolError::InvalidInput(e));
+        }
+        warn!("{e}");
+    }
+    info!("Write MAC address {mac}");
+    let m = mac.0;
+    let d = [&m[..], &CRC32.checksum(&m).to_le_bytes()].concat();
+    send(port, Command::EfuseWriteMac, &d)?;
+    if is_dry_run() {
+        return Ok(());
+    }
+    let got = read_mac(port)?;
+    if got != mac {
+        return Err(ProtocolError::VerifyFailed(format!(
+            "MAC address reads back as {got}, wrote {mac}"
+        )));
+    }
+    Ok(())
 }
 
-pub fn reenable_log(port: &mut Port) {
-    let a: u32 = 0x5c;
-    let mut cfg = &SwConfig0::new().with_uart_log_reopen(true);
+pub fn reenable_log(port: &mut Port) -> Result<()> {
+    let a = efuses::SW_CONFIG0_OFFSET;
+    let cfg = &SwConfig0::new().with_uart_log_reopen(true);
     let v = cfg.into_bits();
-    set_efuse(port, a, v);
+    set_efuse(port, a, v)
 }
 
-pub fn get_info(port: &mut Port) {
-    let bi = get_boot_info(port);
+/// Tell whether the `Log` command will work, from the eFuses.
+pub fn check_log(port: &mut Port) -> Result<bool> {
+    let f = read_efuse_block0(port)?;
+    let sw_cfg = f.sw_config;
+    let cfg0 = sw_cfg.sw_config0;
+    let dis = cfg0.uart_log_disable();
+    let reopen = cfg0.uart_log_reopen();
+    let enabled = cfg0.log_enabled();
+    info!("ROM log: {enabled} (uart_log_disable: {dis}, uart_log_reopen: {reopen})");
+    if !enabled {
+        warn!("The log command will return nothing, run reenable-log first");
+    }
+    Ok(enabled)
+}
+
+pub fn get_info(port: &mut Port) -> Result<()> {
+    let bi = get_boot_info(port)?;
     info!("Boot info: {bi}");
 
-    get_flash_sha(port, &bi);
-    get_efuses(port);
+    get_flash_sha(port, &bi)?;
+    let ef = get_efuses(port)?;
+    if let Ok((b, _)) = EfuseBlock0::read_from_prefix(&ef) {
+        info!("Capabilities:\n{}", b.capabilities());
+    }
+    let log = check_log(port)?;
+    let cfg0 = bi.sw_config0;
+    let fused = EfuseBlock0::read_from_prefix(&ef).is_ok_and(|(b, _)| {
+        let cfg = b.sw_config;
+        let cfg0 = cfg.sw_config0;
+        cfg0.usb_boot_enable()
+    });
+    if cfg0.usb_boot_enable() || fused {
+        warn_usb_boot(port, &bi, log)?;
+    }
+    Ok(())
+}
+
+/// Warn that USB boot is enabled, which is broken in one ROM build. Only the
+/// ROM log has the build date, the driver version is logged to compare.
+fn warn_usb_boot(port: &mut Port, bi: &BootInfo, log_enabled: bool) -> Result<()> {
+    let date = efuses::USB_BOOT_BROKEN_ROM;
+    let rom_drv = bi.rom_driver_version;
+    if !log_enabled {
+        warn!("USB boot is enabled, which is broken in ROM version {date}. Without the ROM log, this ROM's build is unknown (driver version {rom_drv:08x}).");
+        return Ok(());
+    }
+    let log = send_and_retrieve(port, Command::LogRead, &[])?;
+    if String::from_utf8_lossy(&log).contains(date) {
+        warn!("USB boot is enabled and this is ROM version {date} (driver version {rom_drv:08x}), in which it is broken. Boot from UART or flash only.");
+    } else {
+        info!("USB boot is enabled, the ROM log does not show the broken version {date}");
+    }
+    Ok(())
+}
+
+/// Boot info, eFuses and log status as JSON, keyed by the struct field names
+pub fn info_json(port: &mut Port) -> Result<String> {
+    let bi = crate::json::from_debug(&get_boot_info(port)?);
+    let ef = efuses::json(&get_efuses(port)?);
+    let log = check_log(port)?;
+    Ok(format!(
+        "{{\"boot_info\":{bi},\"efuses\":{ef},\"log_enabled\":{log}}}"
+    ))
+}
+
+/// JEDEC ID as returned by `get_flash_id` as JSON
+pub fn flash_id_json(id: &FlashInfo) -> String {
+    let (m, d) = (id.manufacturer, id.device);
+    let c = match id.capacity_bytes {
+        Some(c) => c.to_string(),
+        None => "null".to_string(),
+    };
+    format!("{{\"manufacturer\":{m},\"device\":{d},\"capacity_bytes\":{c}}}")
+}
+
+fn read_flash_chunk(port: &mut Port, addr: u32, size: u32) -> Result<Vec<u8>> {
+    let data = [addr.to_le_bytes(), size.to_le_bytes()].concat();
+    send_and_retrieve(port, Command::FlashRead, &data)
 }
 
-pub fn dump_flash(port: &mut Port, offset: u32, size: u32, file: &str) -> std::io::Result<()> {
-    get_flash_id(port);
+/// Read `size` bytes from flash at `offset`. Expects the flash to be set up,
+/// e.g. via `get_flash_id`.
+pub fn read_flash(port: &mut Port, offset: u32, size: u32) -> Result<Vec<u8>> {
+    let mut res = Vec::<u8>::with_capacity(size as usize);
+    let cs = chunk_size();
+    for a in (offset..offset + size).step_by(cs as usize) {
+        let l = cs.min(offset + size - a);
+        debug!("Now reading {l} bytes from {a:08x}");
+        res.extend_from_slice(&read_flash_chunk(port, a, l)?);
+    }
+    Ok(res)
+}
+
+// Report throughput and how much of the time was spent beyond just moving the
+// bytes over the line, e.g. in commands, acknowledgements and flash access.
+fn report_throughput(port: &Port, what: &str, bytes: usize, elapsed: Duration) {
+    let secs = elapsed.as_secs_f32();
+    let kib = bytes as f32 / 1024.0 / secs;
+    let baud = port.baud_rate().unwrap_or(BAUD_RATE);
+    // 8N1: 10 bits on the line per byte
+    let line = bytes as f32 * 10.0 / baud as f32;
+    let overhead = (secs - line).max(0.0);
+    info!("{what} {bytes} bytes in {secs:.2}s, {kib:.1} KiB/s (transfer {line:.2}s, overhead {overhead:.2}s)");
+}
+
+// Run `op` and log how long it took, with the throughput if it moved `bytes`.
+fn timed<T>(
+    port: &mut Port,
+    what: &str,
+    bytes: usize,
+    op: impl FnOnce(&mut Port) -> Result<T>,
+) -> Result<T> {
+    let start = Instant::now();
+    let r = op(port)?;
+    let elapsed = start.elapsed();
+    if bytes > 0 {
+        report_throughput(port, what, bytes, elapsed);
+    } else {
+        info!("{what} in {:.2}s", elapsed.as_secs_f32());
+    }
+    Ok(r)
+}
+
+fn request_flash_chunk(port: &mut Port, addr: u32, size: u32) -> Result<()> {
+    let data = [addr.to_le_bytes(), size.to_le_bytes()].concat();
+    send_cmd(port, Command::FlashRead, &data)
+}
+
+fn receive_flash_chunk(port: &mut Port) -> Result<Vec<u8>> {
+    get_ok(port)?;
+    get_response(port)
+}
+
+/// Progress callback for long transfers, called with the bytes done and total
+pub type Progress<'a> = &'a mut dyn FnMut(u32, u32);
+
+/// Default progress reporting, logging the percentage every 128K
+pub fn log_progress(done: u32, total: u32) {
+    if done.is_multiple_of(0x20 * CHUNK_SIZE) || done == total {
+        let p = done as u64 * 100 / total.max(1) as u64;
+        info!("{p}%");
+    }
+}
+
+/// Dump flash to a file and verify it against the ROM's SHA256. With
+/// `pipeline`, the next read request is sent before the current response is
+/// received, so the device does not idle while the host processes data. With
+/// `resume`, an existing partial dump in `file` is continued.
+pub fn dump_flash(
+    port: &mut Port,
+    offset: u32,
+    size: u32,
+    file: &str,
+    pipeline: bool,
+    resume: bool,
+    progress: Progress,
+) -> Result<()> {
+    get_flash_id(port)?;
+    let mut hash = BankSha::new(offset);
+    let (mut f, done) = if resume && std::path::Path::new(file).exists() {
+        // The prefix is still needed for the SHA256 over the whole range.
+        let prefix = std::fs::read(file)?;
+        let done = prefix.len();
+        if !done.is_multiple_of(CHUNK_SIZE as usize) {
+            return Err(ProtocolError::InvalidInput(format!(
+                "Cannot resume, {file} has {done:#x} bytes, not a multiple of {CHUNK_SIZE:#x}"
+            )));
+        }
+        if done > size as usize {
+            return Err(ProtocolError::InvalidInput(format!(
+                "Cannot resume, {file} has {done:#x} bytes, more than {size:#x}"
+            )));
+        }
+        hash.update(&prefix);
+        info!("Resume after {done:08x} bytes");
+        let f = std::fs::OpenOptions::new().append(true).open(file)?;
+        (f, done as u32)
+    } else {
+        (File::create(file)?, 0)
+    };
     info!("Dump {size:08x} bytes from flash @ {offset:08x}");
-    let mut f = File::create(file)?;
-    for a in (offset..offset + size).step_by(CHUNK_SIZE as usize) {
-        let p = ((a as f32) / (size as f32) * 100.0) as u32;
-        debug!("Now reading from {a:08x}, {p}%");
-        if (a - offset) % (0x20 * CHUNK_SIZE) == 0 {
-            info!("{p}%");
-        }
-        let data: [u8; 8] = [
-            a as u8,
-            (a >> 8) as u8,
-            (a >> 16) as u8,
-            (a >> 24) as u8,
-            CHUNK_SIZE as u8,
-            (CHUNK_SIZE >> 8) as u8,
-            (CHUNK_SIZE >> 16) as u8,
-            (CHUNK_SIZE >> 24) as u8,
-        ];
-        let res = send_and_retrieve(port, Command::FlashRead, &data);
-        f.write_all(&res);
+    let start = Instant::now();
+    let mut bytes = 0;
+    let end = offset + size;
+    let chunks = bank_parts(offset + done, end, chunk_size());
+    let mut bank = FlashBank::default();
+    // Whether the current chunk was requested along with the previous one
+    let mut requested = false;
+    for (i, &(a, l)) in chunks.iter().enumerate() {
+        debug!("Now reading from {a:08x}");
+        if !requested {
+            let o = bank.select(port, a)?;
+            request_flash_chunk(port, o, l)?;
+        }
+        // Only request ahead within the bank, which can only be switched
+        // once the response is in.
+        let next = chunks
+            .get(i + 1)
+            .filter(|&&(na, _)| pipeline && bank.holds(na));
+        requested = next.is_some();
+        if let Some(&(na, nl)) = next {
+            request_flash_chunk(port, na % FLASH_BANK_SIZE, nl)?;
+        }
+        let res = receive_flash_chunk(port)?;
+        bytes += res.len();
+        hash.update(&res);
+        f.write_all(&res)?;
+        progress(a + l - offset, size);
     }
+    bank.restore(port)?;
+    let mode = if pipeline { "pipelined" } else { "sequential" };
+    report_throughput(port, &format!("Read ({mode})"), bytes, start.elapsed());
+    let sha = hash.finalize();
+    let flash_sha = flash_bank_shas(port, offset, size)?;
+    if sha != flash_sha {
+        let (s, f) = (format_shas(&sha), format_shas(&flash_sha));
+        return Err(ProtocolError::VerifyFailed(format!(
+            "dump SHA256 {s}, flash SHA256 {f}, try --no-pipeline"
+        )));
+    }
+    info!("SHA256 verified: {}", format_shas(&sha));
     Ok(())
 }
 
-pub fn flash_image(port: &mut Port, data: &[u8]) {
-    get_flash_id(port);
-
-    // It appears that the mask ROM would delete until the end address
-    // _inclusively_, and always full 4K pages, so subtract 1 here.
-    // The vendor tool does the same, see
-    // <https://github.com/openbouffalo/bflb-mcu-tool>,
-    // `libs/bflb_eflash_loader.py`, `flash_load_main_process`.
-    let l = data.len() - 1;
-    let start = 0u32.to_le_bytes();
-    let end = (l as u32).to_le_bytes();
-    let mut d = Vec::<u8>::new();
-    d.extend_from_slice(&start);
-    d.extend_from_slice(&end);
-    info!("Erase {l} bytes");
-    send(port, Command::FlashErase, &d);
-
-    let cs = CHUNK_SIZE as usize;
-    let full_chunks = l / cs;
+/// Read the boot header region from the start of flash.
+pub fn read_boot_header(port: &mut Port) -> Result<Vec<u8>> {
+    get_flash_id(port)?;
+    info!("Read boot header, {BOOT_HEADER_SIZE} bytes");
+    read_flash(port, 0, BOOT_HEADER_SIZE as u32)
+}
+
+/// Read the newest valid copy of the partition table. Expects the flash to be
+/// set up, e.g. via `get_flash_id`.
+pub fn read_partition_table(port: &mut Port) -> Result<PartitionTable> {
+    let mut copies = Vec::new();
+    for a in PARTITION_TABLE_ADDR {
+        copies.push((a, read_flash(port, a, PARTITION_TABLE_SIZE)?));
+    }
+    let pt = partition::newest(copies.iter().map(|(a, d)| (*a, d.as_slice())))
+        .map_err(ProtocolError::InvalidResponse)?;
+    Ok(pt)
+}
+
+/// Read the same flash region `count` times to qualify the link, comparing
+/// every read against the first one and the first one against the ROM's SHA256.
+pub fn stress_test(port: &mut Port, offset: u32, size: u32, count: u32) -> Result<()> {
+    let count = count.max(1);
+    get_flash_id(port)?;
+    let mut failures = 0;
+    let first = read_flash(port, offset, size)?;
+    let sha: [u8; 32] = Sha256::digest(&first).into();
+    if first.len() != size as usize {
+        error!("Iteration 0: got {} of {size} bytes", first.len());
+        failures += 1;
+    } else if sha != flash_sha(port, offset, size)? {
+        error!("Iteration 0: data does not match the flash SHA256");
+        failures += 1;
+    }
+    for i in 1..count {
+        let d = read_flash(port, offset, size)?;
+        if d.len() != first.len() {
+            let (l, f) = (d.len(), first.len());
+            error!("Iteration {i}: got {l} bytes, the first read {f}");
+            failures += 1;
+        } else if let Some(o) = first.iter().zip(&d).position(|(a, b)| a != b) {
+            let a = offset + o as u32;
+            error!("Iteration {i}: mismatch @ {a:08x}");
+            failures += 1;
+        } else {
+            debug!("Iteration {i}: OK");
+        }
+    }
+    if failures > 0 {
+        return Err(ProtocolError::VerifyFailed(format!(
+            "{failures} of {count} reads failed"
+        )));
+    }
+    info!("All {count} reads of {size:08x} bytes @ {offset:08x} are identical");
+    Ok(())
+}
+
+// Candidates for the baud rate matrix
+const MATRIX_BAUD_RATES: [u32; 9] = [
+    115_200, 230_400, 460_800, 500_000, 921_600, 1_000_000, 1_500_000, 2_000_000, 3_000_000,
+];
+const MATRIX_ROUND_TRIPS: usize = 5;
+
+fn baud_round_trips(port: &str, baud: u32) -> Result<Vec<Duration>> {
+    let mut port = serialport::new(port, baud).timeout(HALF_SEC).open()?;
+    // The ROM only detects the baud rate once, so start over for each rate.
+    reset_into_bootloader(&mut port);
+    handshake(&mut port)?;
+    (0..MATRIX_ROUND_TRIPS)
+        .map(|_| {
+            let start = Instant::now();
+            get_boot_info(&mut port)?;
+            Ok(start.elapsed())
+        })
+        .collect()
+}
+
+/// Try the handshake and a few round trips at each candidate baud rate and
+/// report all of them, for the adapter and board at hand.
+pub fn baud_matrix(port: &str) {
+    let ms = |d: &Duration| d.as_secs_f32() * 1000.0;
+    let mut table = "Baud rate  Result  Latency min/avg/max".to_string();
+    for baud in MATRIX_BAUD_RATES {
+        info!("Try {baud} baud");
+        let row = match baud_round_trips(port, baud) {
+            Ok(l) => {
+                let min = l.iter().map(ms).fold(f32::MAX, f32::min);
+                let max = l.iter().map(ms).fold(0.0, f32::max);
+                let avg = l.iter().map(ms).sum::<f32>() / l.len() as f32;
+                format!("{baud:>9}  pass    {min:.1}/{avg:.1}/{max:.1} ms")
+            }
+            Err(e) => format!("{baud:>9}  fail    {e}"),
+        };
+        table += &format!("\n{row}");
+    }
+    info!("{table}");
+}
+
+pub fn flash_usage(port: &mut Port) -> Result<()> {
+    let size = get_flash_size(port)?;
+    let pt = read_partition_table(port)?;
+    debug!("{pt}");
+    let pct = |l: u32| l as f32 / size as f32 * 100.0;
+    let mut used = 0;
+    let mut table = format!(
+        "Flash size: {}K\nName      Slot  Address   Size",
+        size / 1024
+    );
+    for e in &pt.entries {
+        for (i, (a, l)) in e.slots().into_iter().enumerate() {
+            used += l;
+            let p = pct(l);
+            table += &format!("\n{:9} {i}     {a:08x}  {l:08x}  {p:5.1}%", e.name());
+        }
+    }
+    let free = size.saturating_sub(used);
+    let (pu, pf) = (pct(used), pct(free));
+    info!("{table}\nAllocated: {used:08x} ({pu:.1}%), free: {free:08x} ({pf:.1}%)");
+    Ok(())
+}
+
+/// Dump the whole flash and write each partition slot to its own file in
+/// `dir`, along with a manifest listing them.
+pub fn extract_partitions(port: &mut Port, dir: &str) -> Result<()> {
+    let size = get_flash_size(port)?;
+    info!("Read full flash, {}K", size / 1024);
+    let flash = read_flash(port, 0, size)?;
+    let copies = PARTITION_TABLE_ADDR.map(|a| {
+        let a0 = a as usize;
+        (a, &flash[a0..a0 + PARTITION_TABLE_SIZE as usize])
+    });
+    let pt = partition::newest(copies).map_err(ProtocolError::InvalidResponse)?;
+    info!("{pt}");
+
+    std::fs::create_dir_all(dir)?;
+    let (v, age) = (pt.version, pt.age);
+    let mut manifest = format!("# Partition table version {v}, age {age}\n");
+    manifest += "# name slot address size file\n";
+    for e in &pt.entries {
+        let name = e.name().replace(['/', '\\'], "_");
+        for (i, (a, l)) in e.slots().into_iter().enumerate() {
+            let Some(d) = flash.get(a as usize..(a as usize).saturating_add(l as usize)) else {
+                warn!("{name} slot {i}: {l:08x} @ {a:08x} exceeds the flash, skipping");
+                continue;
+            };
+            let file = format!("{name}.{i}.bin");
+            std::fs::write(format!("{dir}/{file}"), d)?;
+            info!("{name} slot {i}: {l:08x} bytes @ {a:08x} -> {file}");
+            manifest += &format!("{name} {i} {a:08x} {l:08x} {file}\n");
+        }
+    }
+    std::fs::write(format!("{dir}/manifest.txt"), manifest)?;
+    Ok(())
+}
+
+/// Read the image from flash and write each segment to its own file in `dir`,
+/// named after the core it is the entry point of, along with a manifest.
+pub fn extract_segments(port: &mut Port, dir: &str) -> Result<()> {
+    let flash_size = get_flash_size(port)?;
+    let mut image = read_flash(port, 0, BOOT_HEADER_SIZE as u32)?;
+    let invalid = |e: String| ProtocolError::InvalidResponse(format!("Image on flash: {e}"));
+    let header = BootHeader::parse(&image).map_err(|e| invalid(e.to_string()))?;
+    let count = header.segment_count().map_err(invalid)?;
+    // Only the header and segments are of interest, leave the gap erased.
+    image.resize(SEGMENTS_OFFSET, 0xff);
+    let mut o = SEGMENTS_OFFSET as u32;
+    for i in 0..count {
+        let sh = read_flash(port, o, SEGMENT_HEADER_SIZE as u32)?;
+        let Ok(h) = SegmentHeader::read_from_bytes(&sh) else {
+            return Err(invalid(format!("Short segment header read: {sh:02x?}")));
+        };
+        let size = h.size;
+        o += SEGMENT_HEADER_SIZE as u32;
+        if o.saturating_add(size) > flash_size {
+            return Err(invalid(format!(
+                "Segment {i}: {size:08x} bytes @ {o:08x} exceed the flash"
+            )));
+        }
+        image.extend_from_slice(&sh);
+        image.extend_from_slice(&read_flash(port, o, size)?);
+        o += size;
+    }
+    let img = Image::parse(&image).map_err(invalid)?;
+
+    std::fs::create_dir_all(dir)?;
+    let entries = img.header.entry_points();
+    let mut manifest = "# name address size file\n".to_string();
+    for (n, s) in img.segments.iter().enumerate() {
+        let a = s.header.address;
+        let l = s.header.size;
+        let name = match entries.iter().find(|(_, e)| *e == a) {
+            Some((core, _)) => core.to_lowercase(),
+            None => format!("segment{n}"),
+        };
+        let file = format!("{name}.bin");
+        std::fs::write(format!("{dir}/{file}"), s.data)?;
+        info!("Segment {n}: {l} bytes @ {a:08x} -> {file}");
+        manifest += &format!("{name} {a:08x} {l:08x} {file}\n");
+    }
+    std::fs::write(format!("{dir}/manifest.txt"), manifest)?;
+    Ok(())
+}
+
+/// Everything the tool hardcodes about the chip
+pub fn chip_info() -> String {
+    let mem = format!(
+        "OCRAM base:   {:08x}\nD0 RAM base:  {:08x}",
+        crate::mem_map::OCRAM_BASE,
+        crate::mem_map::D0_RAM_BASE
+    );
+    let load = format!(
+        "Load addresses: M0 {M0_LOAD_ADDR:08x}, D0 {D0_LOAD_ADDR:08x}, LP {LP_LOAD_ADDR:08x}"
+    );
+    let hs = HandshakeParams::DEFAULT;
+    let proto = format!(
+        "Default baud rate: {BAUD_RATE} ({MIN_BAUD_RATE}-{MAX_BAUD_RATE})\nHandshake: {} x 'U', {} ms, magic {MAGIC:02x?}\nChunk size: {CHUNK_SIZE} (up to {MAX_CHUNK_SIZE})",
+        hs.sync_len, hs.delay_ms
+    );
+    let boot = format!(
+        "Boot header: magic {:?}, {BOOT_HEADER_SIZE} bytes\nSegments offset: {SEGMENTS_OFFSET:#x}",
+        String::from_utf8_lossy(crate::boot::BOOT_MAGIC)
+    );
+    let slots = EFUSE_SIZE / EFUSE_SLOT_SIZE;
+    let ef = format!(
+        "eFuses: {slots} slots of {EFUSE_SLOT_SIZE:#x} bytes\n{}",
+        efuses::layout()
+    );
+    format!("{mem}\n{load}\n\n{proto}\n\n{boot}\n\n{ef}")
+}
+
+/// Look for common causes of a board not booting and optionally reflash it.
+pub fn recover(port: String, baud: u32, image: Option<Vec<u8>>, confirm: bool) -> Result<()> {
+    let mut port = open(port, baud)?;
+    reset_into_bootloader(&mut port);
+    handshake(&mut port)?;
+
+    let mut problems = Vec::<&str>::new();
+    let f = read_efuse_block0(&mut port)?;
+    let sw_cfg = f.sw_config;
+    let cfg0 = sw_cfg.sw_config0;
+    let media = !cfg0.media_boot_disable();
+    let sd = cfg0.sdh_en();
+    let usb = cfg0.usb_boot_enable();
+    info!("Boot sources: flash/SD: {media}, SD card: {sd}, USB: {usb}, UART: true");
+    if !media {
+        problems.push("Boot from flash/SD is disabled by eFuse, reflashing will not help");
+    }
+    if usb {
+        problems.push("USB boot is enabled by eFuse, which is broken in some ROM versions");
+    }
+
+    let h = read_boot_header(&mut port)?;
+    if h.iter().all(|&b| b == 0xff) {
+        problems.push("Flash is erased, there is no boot image");
+    } else {
+        let Ok(bh) = BootHeader::read_from_bytes(&h) else {
+            return Err(ProtocolError::InvalidResponse(format!(
+                "Short boot header read: {} bytes",
+                h.len()
+            )));
+        };
+        if !bh.has_magic() {
+            problems.push("Boot header magic is wrong, there is no valid boot image");
+        } else if !bh.crc_ok() {
+            problems.push("Boot header CRC is wrong, the image is corrupted");
+        }
+    }
+
+    if problems.is_empty() {
+        info!("No common cause for a bricked board found");
+    }
+    for p in &problems {
+        warn!("{p}");
+    }
+    let Some(image) = image else {
+        if !problems.is_empty() {
+            info!("Pass a known-good image to erase and reflash the flash");
+        }
+        return Ok(());
+    };
+    if !confirm {
+        warn!("Not reflashing without confirmation");
+        return Ok(());
+    }
+    flash_image(
+        &mut port,
+        &image,
+        None,
+        WRITE_RETRIES,
+        false,
+        &mut log_progress,
+    )
+}
+
+/// Default for how often a flash chunk write is retried when the ROM fails it
+pub const WRITE_RETRIES: u32 = 3;
+
+// Write a chunk, sending it again up to `retries` times on `FL`.
+fn write_flash_chunk(port: &mut Port, offset: u32, chunk: &[u8], retries: u32) -> Result<()> {
+    let d = [offset.to_le_bytes().as_slice(), chunk].concat();
+    let mut attempt = 0;
+    loop {
+        match send(port, Command::FlashWrite, &d) {
+            Err(e @ ProtocolError::CommandFailed(_)) if attempt < retries => {
+                attempt += 1;
+                warn!("Chunk @ {offset:08x}: {e}, retry {attempt}/{retries}");
+                STATS.flash_write_retries.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                error!("Chunk @ {offset:08x} failed after {attempt} retries");
+                return Err(e);
+            }
+            Ok(()) => return Ok(()),
+        }
+    }
+}
+
+/// Select the 16M bank of the flash that the following flash commands
+/// address, 0 for the first one.
+pub fn switch_flash_bank(port: &mut Port, bank: u32) -> Result<()> {
+    info!("Switch to flash bank {bank}");
+    send(port, Command::FlashSwitchBank, &bank.to_le_bytes())
+}
+
+/// Flash the ROM addresses at once; larger flashes are switched in banks.
+pub const FLASH_BANK_SIZE: u32 = 16 * 1024 * 1024;
+
+// Split `start..end` into parts of up to `max` bytes within one bank each.
+fn bank_parts(start: u32, end: u32, max: u32) -> Vec<(u32, u32)> {
+    let mut parts = Vec::new();
+    let mut a = start;
+    while a < end {
+        let l = max.min(FLASH_BANK_SIZE - a % FLASH_BANK_SIZE).min(end - a);
+        parts.push((a, l));
+        a += l;
+    }
+    parts
+}
+
+// The bank selected on the device, 0 unless a range reaches beyond it.
+// `restore` switches back to 0 once the range is done.
+#[derive(Default)]
+struct FlashBank(u32);
+
+impl FlashBank {
+    // Switch to the bank of `addr` if needed, returning the offset within it.
+    fn select(&mut self, port: &mut Port, addr: u32) -> Result<u32> {
+        let bank = addr / FLASH_BANK_SIZE;
+        if bank != self.0 {
+            switch_flash_bank(port, bank)?;
+            self.0 = bank;
+        }
+        Ok(addr % FLASH_BANK_SIZE)
+    }
+
+    fn holds(&self, addr: u32) -> bool {
+        addr / FLASH_BANK_SIZE == self.0
+    }
+
+    fn restore(self, port: &mut Port) -> Result<()> {
+        if self.0 != 0 {
+            switch_flash_bank(port, 0)?;
+        }
+        Ok(())
+    }
+}
+
+// SHA256 of flash data starting at an offset, one per bank it covers, as the
+// ROM only hashes within a bank.
+struct BankSha {
+    addr: u32,
+    parts: Vec<Sha256>,
+}
+
+impl BankSha {
+    fn new(offset: u32) -> Self {
+        Self {
+            addr: offset,
+            parts: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.parts.is_empty() || self.addr.is_multiple_of(FLASH_BANK_SIZE) {
+                self.parts.push(Sha256::new());
+            }
+            let room = FLASH_BANK_SIZE - self.addr % FLASH_BANK_SIZE;
+            let (d, rest) = data.split_at(data.len().min(room as usize));
+            if let Some(h) = self.parts.last_mut() {
+                h.update(d);
+            }
+            self.addr = self.addr.wrapping_add(d.len() as u32);
+            data = rest;
+        }
+    }
+
+    fn finalize(self) -> Vec<[u8; 32]> {
+        self.parts
+            .into_iter()
+            .map(|h| h.finalize().into())
+            .collect()
+    }
+}
+
+// The ROM's SHA256 of `size` bytes of flash at `offset`, one per bank
+fn flash_bank_shas(port: &mut Port, offset: u32, size: u32) -> Result<Vec<[u8; 32]>> {
+    let mut bank = FlashBank::default();
+    let mut shas = Vec::new();
+    for (a, l) in bank_parts(offset, offset + size, FLASH_BANK_SIZE) {
+        let o = bank.select(port, a)?;
+        shas.push(flash_sha(port, o, l)?);
+    }
+    bank.restore(port)?;
+    Ok(shas)
+}
+
+fn format_shas(shas: &[[u8; 32]]) -> String {
+    let s: Vec<String> = shas.iter().map(|s| format!("{s:02x?}")).collect();
+    s.join(", ")
+}
+
+// Erase `start..end` bank by bank. The ROM takes the end address inclusively,
+// see `flash_image_from`.
+fn erase_range(port: &mut Port, start: u32, end: u32) -> Result<()> {
+    let mut bank = FlashBank::default();
+    timed(port, "Erased", 0, |port| {
+        for (a, l) in bank_parts(start, end, FLASH_BANK_SIZE) {
+            let o = bank.select(port, a)?;
+            let d = [o.to_le_bytes(), (o + l - 1).to_le_bytes()].concat();
+            send(port, Command::FlashErase, &d)?;
+        }
+        bank.restore(port)
+    })
+}
+
+/// Erase the whole flash. Expects the flash to be set up, e.g. via
+/// `get_flash_id`.
+pub fn chip_erase(port: &mut Port) -> Result<()> {
+    info!("Erase the whole flash, this may take a while");
+    timed(port, "Erased", 0, |port| {
+        send(port, Command::FlashChipErase, &[])
+    })
+}
+
+/// Erase `size` bytes of flash at `offset`, rounded out to whole sectors as
+/// the flash only erases those anyway. Which erase commands to use for larger
+/// ranges is up to the ROM and the flash config.
+pub fn erase_flash(port: &mut Port, offset: u32, size: u32) -> Result<()> {
+    if size == 0 {
+        return Err(ProtocolError::InvalidInput("Nothing to erase".to_string()));
+    }
+    let flash_size = get_flash_size(port)?;
+    let range = flash::sector_range(offset, size).filter(|&(_, end)| end <= flash_size);
+    let Some((start, end)) = range else {
+        let f = flash::format_size(flash_size);
+        return Err(ProtocolError::InvalidInput(format!(
+            "{size} bytes @ {offset:08x} exceed the {f} flash"
+        )));
+    };
+    if start != offset || end - start != size {
+        warn!("Range is not sector aligned, erasing {start:08x}..{end:08x} instead");
+    }
+    info!("Erase {start:08x}..{end:08x}");
+    erase_range(port, start, end)
+}
+
+// Write `len` bytes from `reader` to the erased flash at `offset`, chunk by
+// chunk, returning their SHA256 per bank and the first chunk.
+fn write_chunks(
+    port: &mut Port,
+    offset: u32,
+    reader: &mut dyn std::io::Read,
+    len: u32,
+    write_retries: u32,
+    progress: Progress,
+) -> Result<(Vec<[u8; 32]>, Vec<u8>)> {
+    let mut cs = chunk_size();
     info!("Send chunks");
-    for c in 0..full_chunks {
-        let o = c * cs;
-        let chunk = &data[o..o + cs];
-        let offset = (o as u32).to_le_bytes();
-        let mut d = Vec::<u8>::new();
-        d.extend_from_slice(&offset);
-        d.extend_from_slice(chunk);
-        info!("Write chunk {c} at offset {o:08x}");
-        send(port, Command::FlashWrite, &d);
+    let start = Instant::now();
+    let mut hash = BankSha::new(offset);
+    let mut bank = FlashBank::default();
+    let mut first = Vec::new();
+    let mut chunk = vec![0u8; cs as usize];
+    let mut o = 0;
+    while o < len {
+        let a = offset + o;
+        let room = FLASH_BANK_SIZE - a % FLASH_BANK_SIZE;
+        let chunk = &mut chunk[..cs.min(len - o).min(room) as usize];
+        reader.read_exact(chunk)?;
+        hash.update(chunk);
+        if o == 0 {
+            first = chunk.to_vec();
+        }
+        debug!("Write chunk at offset {a:08x}, {} bytes", chunk.len());
+        let a = bank.select(port, a)?;
+        if let Err(e) = write_flash_chunk(port, a, chunk, write_retries) {
+            if cs == CHUNK_SIZE {
+                return Err(e);
+            }
+            fall_back_chunk_size(port, &e);
+            cs = CHUNK_SIZE;
+            // Bytes the failed write did program get the same values again.
+            for (i, c) in chunk.chunks(cs as usize).enumerate() {
+                write_flash_chunk(port, a + i as u32 * cs, c, write_retries)?;
+            }
+        }
+        o += chunk.len() as u32;
+        progress(o, len);
+    }
+    bank.restore(port)?;
+    report_throughput(port, "Wrote", len as usize, start.elapsed());
+    Ok((hash.finalize(), first))
+}
+
+// Compare the ROM's SHA256 of `len` bytes of flash at `offset` with `sha`.
+fn check_flash_sha(port: &mut Port, offset: u32, len: u32, sha: Vec<[u8; 32]>) -> Result<()> {
+    let flash_sha = flash_bank_shas(port, offset, len)?;
+    if flash_sha != sha {
+        let (f, s) = (format_shas(&flash_sha), format_shas(&sha));
+        return Err(ProtocolError::VerifyFailed(format!(
+            "flash SHA256 {f}, image SHA256 {s}"
+        )));
+    }
+    info!("SHA256 verified: {}", format_shas(&sha));
+    Ok(())
+}
+
+/// Write `data` to flash at `offset`, e.g. to update a single partition,
+/// erasing only the sectors it covers. `offset` has to be sector aligned so
+/// that nothing before it is erased. With `verify`, compare the ROM's SHA256.
+pub fn write_flash(
+    port: &mut Port,
+    offset: u32,
+    data: &[u8],
+    write_retries: u32,
+    verify: bool,
+    progress: Progress,
+) -> Result<()> {
+    if !offset.is_multiple_of(flash::SECTOR_SIZE) {
+        return Err(ProtocolError::InvalidInput(format!(
+            "Offset {offset:08x} is not aligned to {} sectors",
+            flash::format_size(flash::SECTOR_SIZE)
+        )));
+    }
+    let len = data.len() as u32;
+    if len == 0 {
+        return Err(ProtocolError::InvalidInput("Data is empty".to_string()));
+    }
+    let _span = info_span!("write_flash", offset, bytes = len).entered();
+    // Sets up the flash and checks the range against its size
+    erase_flash(port, offset, len)?;
+    let mut r = data;
+    let (sha, _) = write_chunks(port, offset, &mut r, len, write_retries, progress)?;
+    if is_dry_run() || !verify {
+        return Ok(());
     }
-    if data.len() % cs > 0 {
-        let remaining = &data[full_chunks * cs..];
-        info!("Send remaining data, {} bytes", remaining.len());
-        send(port, Command::FlashWrite, remaining);
+    check_flash_sha(port, offset, len, sha)
+}
+
+/// Write an image to flash, erasing only the range it covers or, with
+/// `full_erase`, the whole chip first.
+pub fn flash_image(
+    port: &mut Port,
+    data: &[u8],
+    flash_config: Option<&[u8]>,
+    write_retries: u32,
+    full_erase: bool,
+    progress: Progress,
+) -> Result<()> {
+    let len = data.len() as u32;
+    let mut r = data;
+    flash_image_from(
+        port,
+        &mut r,
+        len,
+        flash_config,
+        write_retries,
+        full_erase,
+        progress,
+    )
+}
+
+/// Like `flash_image`, but read the `len` bytes of the image from `reader`
+/// one chunk at a time instead of keeping it all in memory.
+pub fn flash_image_from(
+    port: &mut Port,
+    reader: &mut dyn std::io::Read,
+    len: u32,
+    flash_config: Option<&[u8]>,
+    write_retries: u32,
+    full_erase: bool,
+    progress: Progress,
+) -> Result<()> {
+    let _span = info_span!("flash_image", bytes = len).entered();
+    let size = get_flash_size(port)?;
+    if let Some(c) = flash_config {
+        set_flash_config(port, c)?;
+    }
+    if len > size {
+        let k = size / 1024;
+        return Err(ProtocolError::InvalidInput(format!(
+            "Image of {len} bytes does not fit into the {k}K flash"
+        )));
+    }
+    if len == 0 {
+        return Err(ProtocolError::InvalidInput("Image is empty".to_string()));
+    }
+
+    if full_erase {
+        chip_erase(port)?;
+    } else {
+        // It appears that the mask ROM would delete until the end address
+        // _inclusively_, and always full 4K pages, so `erase_range` subtracts 1.
+        // The vendor tool does the same, see
+        // <https://github.com/openbouffalo/bflb-mcu-tool>,
+        // `libs/bflb_eflash_loader.py`, `flash_load_main_process`.
+        info!("Erase {} bytes", len - 1);
+        erase_range(port, 0, len)?;
+    }
+
+    let (sha, first) = write_chunks(port, 0, reader, len, write_retries, progress)?;
+    if is_dry_run() {
+        return Ok(());
+    }
+    check_flash_sha(port, 0, len, sha)?;
+
+    // A corrupted header sector is a common cause of a bricked board, so check
+    // what actually landed on the device, unless raw data was flashed.
+    if BootHeader::parse(&first).is_ok() {
+        verify_boot_header(port)?;
+        info!("Boot header on flash: OK");
+    }
+    Ok(())
+}
+
+/// Show the per-core configs of the image on flash.
+pub fn show_cpu_configs(port: &mut Port) -> Result<()> {
+    let h = read_boot_header(port)?;
+    match BootHeader::parse(&h) {
+        Ok(bh) => info!("{}", bh.cpu_configs()),
+        Err(e) => error!("{e}"),
+    }
+    Ok(())
+}
+
+/// Read the boot header back from flash and check its magic and CRC. Expects
+/// the flash to be set up, e.g. via `get_flash_id`.
+pub fn verify_boot_header(port: &mut Port) -> Result<()> {
+    let h = read_flash(port, 0, BOOT_HEADER_SIZE as u32)?;
+    let failed = |e: String| ProtocolError::VerifyFailed(format!("Boot header on flash: {e}"));
+    let bh = BootHeader::parse(&h).map_err(|e| failed(e.to_string()))?;
+    if !bh.crc_ok() {
+        return Err(failed("CRC mismatch".to_string()));
     }
+    Ok(())
 }
 
-pub fn read_log(port: &mut Port) {
-    let res = send_and_retrieve(port, Command::LogRead, &[]);
+pub fn read_log(port: &mut Port) -> Result<()> {
+    let res = send_and_retrieve(port, Command::LogRead, &[])?;
     match str::from_utf8(&res) {
         Ok(s) => {
             info!("=== Log start\n{s}");
@@ -493,24 +2638,76 @@ pub fn read_log(port: &mut Port) {
             error!("Cannot parse log as UTF-8: {e}\n{details}");
         }
     }
+    Ok(())
 }
 
-pub fn send_segment(port: &mut Port, s: &crate::boot::Segment) {
+pub fn send_segment(port: &mut Port, s: &crate::boot::Segment) -> Result<()> {
     info!("Send segment header: {:#08x?}", s.header);
-    let res = send_and_retrieve(port, Command::LoadSegHeader, s.header.as_bytes());
+    let res = send_and_retrieve(port, Command::LoadSegHeader, s.header.as_bytes())?;
     debug!("Got: {res:02x?}");
-    let cs = CHUNK_SIZE as usize;
-    let full_chunks = s.data.len() / cs;
+    let mut cs = chunk_size() as usize;
     info!("Send segment data");
-    for c in 0..full_chunks {
-        info!("Send chunk {c}");
-        let o = c * cs;
-        send(port, Command::LoadSegData, &s.data[o..o + cs]);
-    }
-    if s.data.len() % cs > 0 {
-        info!("Send remaining data");
-        send(port, Command::LoadSegData, &s.data[full_chunks * cs..]);
-    }
+    timed(port, "Sent segment", s.data.len(), |port| {
+        let mut o = 0;
+        while o < s.data.len() {
+            let c = &s.data[o..s.data.len().min(o + cs)];
+            info!("Send {} bytes @ {o:#x}", c.len());
+            if let Err(e) = send(port, Command::LoadSegData, c) {
+                if cs == CHUNK_SIZE as usize {
+                    return Err(e);
+                }
+                // Send the same data again in smaller chunks.
+                fall_back_chunk_size(port, &e);
+                cs = CHUNK_SIZE as usize;
+                continue;
+            }
+            o += c.len();
+        }
+        Ok(())
+    })
+}
+
+/// Send the IV for an encrypted image, after the boot header and before the
+/// segments. Like in image files, the ROM expects the IV's CRC32 after it.
+///
+/// Nothing here encrypts segments yet: there is no AES implementation in
+/// this crate, so the data has to be encrypted with the eFuse key beforehand.
+pub fn load_aes_iv(port: &mut Port, iv: &[u8; 16]) -> Result<()> {
+    debug!("Send AES IV");
+    let d = [&iv[..], &CRC32.checksum(iv).to_le_bytes()].concat();
+    send(port, Command::LoadAesIV, &d)
+}
+
+/// Size of an ECDSA public key as in image files: x, then y, 32 bytes each
+pub const PUBLIC_KEY_SIZE: usize = 64;
+
+/// Send the public key and signature of a signed image, after the boot header
+/// and before the AES IV, to the first key slot or, with `second`, the other.
+/// The ROM expects a CRC32 after each, and the signature prefixed with its
+/// length, as in image files.
+///
+/// Signing is not done here, as there is no ECDSA implementation in this
+/// crate; pass the values from an image signed beforehand.
+pub fn load_signature(
+    port: &mut Port,
+    second: bool,
+    public_key: &[u8; PUBLIC_KEY_SIZE],
+    signature: &[u8],
+) -> Result<()> {
+    let (pk_cmd, sig_cmd) = if second {
+        (Command::LoadPublicKey2, Command::LoadSignature2)
+    } else {
+        (Command::LoadPublicKey1, Command::LoadSignature1)
+    };
+    debug!("Send public key");
+    let d = [&public_key[..], &CRC32.checksum(public_key).to_le_bytes()].concat();
+    send(port, pk_cmd, &d)?;
+    debug!("Send signature");
+    let mut d = (signature.len() as u32).to_le_bytes().to_vec();
+    d.extend_from_slice(signature);
+    let crc = CRC32.checksum(&d);
+    d.extend_from_slice(&crc.to_le_bytes());
+    send(port, sig_cmd, &d)
 }
 
 pub fn run(
@@ -518,30 +2715,135 @@ pub fn run(
     data1: Option<Vec<u8>>,
     data2: Option<Vec<u8>>,
     data3: Option<Vec<u8>>,
-) {
-    let s1 = data1.as_ref().map(|d| Segment::new(M0_LOAD_ADDR, d));
-    let s2 = data2.as_ref().map(|d| Segment::new(D0_LOAD_ADDR, d));
-    let s3 = data3.as_ref().map(|d| Segment::new(LP_LOAD_ADDR, d));
+    addrs: LoadAddresses,
+    ignore: IgnoreChecks,
+) -> Result<()> {
+    let align = |a, d| align_segment(a, d).map_err(ProtocolError::InvalidInput);
+    let data1 = data1.map(|d| align(addrs.m0, d)).transpose()?;
+    let data2 = data2.map(|d| align(addrs.d0, d)).transpose()?;
+    let data3 = data3.map(|d| align(addrs.lp, d)).transpose()?;
 
-    let header = BootHeader::new(s1, s2, s3);
+    let s1 = data1.as_ref().map(|d| Segment::new(addrs.m0, d));
+    let s2 = data2.as_ref().map(|d| Segment::new(addrs.d0, d));
+    let s3 = data3.as_ref().map(|d| Segment::new(addrs.lp, d));
+    for s in [s1, s2, s3].iter().flatten() {
+        crate::boot::check_segment(s).map_err(ProtocolError::InvalidInput)?;
+    }
+
+    let header = BootHeader::new(s1, s2, s3).with_ignore(ignore);
+    // Compare with the device on `image hash error`.
+    let sha = header.segments_sha256();
+    info!("Segments SHA256: {sha:02x?}");
+    let segments: Vec<Segment> = [s1, s2, s3].into_iter().flatten().collect();
+    load_and_run(port, &header, &segments)
+}
+
+/// Write a prebuilt image to SRAM and execute
+pub fn run_image(port: &mut Port, image: &Image) -> Result<()> {
+    load_and_run(port, &image.header, &image.segments)
+}
+
+fn load_and_run(port: &mut Port, header: &BootHeader, segments: &[Segment]) -> Result<()> {
+    let _span = info_span!("run", segments = segments.len()).entered();
     let header_bytes = header.as_bytes();
     let step_size = 8;
     for o in (0..header_bytes.len()).step_by(step_size) {
         debug!("{:08x}: {:02x?}", o, &header_bytes[o..o + step_size]);
     }
     info!("Send boot header");
-    send(port, Command::LoadBootHeader, header_bytes);
-    if let Some(s) = s1 {
-        send_segment(port, &s);
+    send(port, Command::LoadBootHeader, header_bytes)?;
+    let total = segments.iter().map(|s| s.data.len()).sum();
+    timed(port, "Sent all segments", total, |port| {
+        segments.iter().try_for_each(|s| send_segment(port, s))
+    })?;
+    info!("Check image");
+    // The ROM answers with `FL` and e.g. `image hash error` if it rejects the
+    // header or segments, so stop here instead of trying to run it.
+    if let Err(e) = send(port, Command::CheckImage, &[]) {
+        error!("Image rejected, compare the boot header and segments SHA256");
+        return Err(e);
     }
-    if let Some(s) = s2 {
-        send_segment(port, &s);
+    info!("Run image");
+    match send(port, Command::RunImage, &[]) {
+        Err(ProtocolError::Timeout) => {
+            warn!("No status for running the image, it may have started anyway");
+            Ok(())
+        }
+        r => r,
     }
-    if let Some(s) = s3 {
-        send_segment(port, &s);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockPort;
+
+    #[test]
+    fn get_ok_decodes_rom_error() {
+        let mock = MockPort::new();
+        let mut port = mock.port();
+        mock.respond_error(0x0204);
+        match send(&mut port, Command::GetBootInfo, &[]) {
+            Err(ProtocolError::CommandFailed(e)) => {
+                assert_eq!(e.code, 0x0204);
+                assert_eq!(e.category, ErrorCategory::Image);
+                assert_eq!(e.message, "image boot header CRC error");
+            }
+            r => panic!("expected a command error, got {r:?}"),
+        }
+    }
+
+    #[test]
+    fn get_ok_rejects_unexpected_status() {
+        let mock = MockPort::new();
+        let mut port = mock.port();
+        mock.respond(b"XY");
+        match send(&mut port, Command::GetBootInfo, &[]) {
+            Err(ProtocolError::UnexpectedStatus(s)) => assert_eq!(&s, b"XY"),
+            r => panic!("expected an unexpected status, got {r:?}"),
+        }
+    }
+
+    #[test]
+    fn handshake_retries_until_ok() {
+        let mock = MockPort::new();
+        let mut port = mock.port();
+        // A full scan window of noise fails the first attempt.
+        mock.respond(&[0x55; HANDSHAKE_SCAN_WINDOW]);
+        mock.respond(&[0x00, 0x55, b'O', b'K']);
+        handshake(&mut port).unwrap();
+        assert_eq!(mock.pending(), 0);
+        let w = mock.take_written();
+        let p = HandshakeParams::DEFAULT;
+        let attempt = p.sync_len + p.magic_repeats * MAGIC.len();
+        assert_eq!(w.len(), 2 * attempt);
+        assert!(w.ends_with(&MAGIC));
+    }
+
+    #[test]
+    fn get_boot_info_parses_response() {
+        let mock = MockPort::new();
+        let mut port = mock.port();
+        let mut d = vec![0u8; std::mem::size_of::<BootInfo>()];
+        d[0..4].copy_from_slice(&0x0100_0002u32.to_le_bytes());
+        d[12..18].copy_from_slice(&[0x66, 0x55, 0x44, 0xcf, 0x0e, 0xb4]);
+        mock.respond_data(&d);
+        let bi = get_boot_info(&mut port).unwrap();
+        let (v, macx) = (bi.rom_driver_version, bi.wifi_mac_x);
+        assert_eq!(v, 0x0100_0002);
+        assert_eq!(macx.mac_addr(), 0xb40e_cf44_5566);
+        // Command 0x10 without data
+        assert_eq!(mock.take_written()[0], Command::GetBootInfo as u8);
+    }
+
+    #[test]
+    fn get_boot_info_rejects_short_response() {
+        let mock = MockPort::new();
+        let mut port = mock.port();
+        mock.respond_data(&[0; 4]);
+        match get_boot_info(&mut port) {
+            Err(ProtocolError::InvalidResponse(e)) => assert!(e.contains("size: 4")),
+            r => panic!("expected an invalid response, got {r:?}"),
+        }
     }
-    info!("Check image");
-    send(port, Command::CheckImage, &[]);
-    info!("Run image");
-    send(port, Command::RunImage, &[]);
 }
@@ -0,0 +1,91 @@
+// Compare the boot header against a checked-in snapshot, reading the fields
+// at the offsets of the BL808 layout rather than through our structs.
+//
+// The snapshot was produced by this tool, `build-image -m m0.bin --flash-id
+// ef4018` with the 256 bytes 00..ff as m0.bin, so it only catches changes to
+// what we build, e.g. reordering or resizing a packed field. It is not a
+// vendor reference: checking our layout against the vendor tool still needs
+// a header built with it.
+use bl_boot::boot::{
+    build_image, BootHeader, IgnoreChecks, Segment, BOOT_HEADER_SIZE, BOOT_MAGIC, CRC32,
+    M0_LOAD_ADDR, SEGMENTS_OFFSET, SEGMENT_HEADER_SIZE,
+};
+use sha2::{Digest, Sha256};
+
+const SNAPSHOT: &[u8; BOOT_HEADER_SIZE] = include_bytes!("fixtures/bl808_m0_header_snapshot.bin");
+const FLASH_ID: [u8; 3] = [0xef, 0x40, 0x18];
+
+fn m0_data() -> Vec<u8> {
+    (0..=255).collect()
+}
+
+fn word(o: usize) -> u32 {
+    u32::from_le_bytes(SNAPSHOT[o..o + 4].try_into().unwrap())
+}
+
+#[test]
+fn snapshot_fields() {
+    assert_eq!(&SNAPSHOT[0x00..0x04], BOOT_MAGIC);
+    assert_eq!(word(0x04), 1, "revision");
+
+    // Flash config, 0x08..0x64
+    assert_eq!(&SNAPSHOT[0x08..0x0c], b"FCFG");
+    assert_eq!(SNAPSHOT[0x0c], 0x11, "io_mode");
+    assert_eq!(SNAPSHOT[0x10], 0x66, "reset_en_command");
+    assert_eq!(SNAPSHOT[0x11], 0x99, "reset_command");
+    assert_eq!(SNAPSHOT[0x14], 0x9f, "jedec_id_command");
+    assert_eq!(word(0x60), CRC32.checksum(&SNAPSHOT[0x0c..0x60]));
+
+    // Clock config, 0x64..0x80
+    assert_eq!(&SNAPSHOT[0x64..0x68], b"PCFG");
+    assert_eq!(word(0x7c), CRC32.checksum(&SNAPSHOT[0x68..0x7c]));
+
+    // Boot config, 0x80..0xb0: one segment, hashed with its header
+    assert_eq!(word(0x8c), 1, "segment count");
+    // Segment header: address, size, reserved, CRC32 over the first three
+    let mut seg = [M0_LOAD_ADDR, 0x100, 0].map(u32::to_le_bytes).concat();
+    seg.extend(CRC32.checksum(&seg).to_le_bytes());
+    let sha: [u8; 32] = Sha256::new()
+        .chain_update(&seg)
+        .chain_update(m0_data())
+        .finalize()
+        .into();
+    assert_eq!(SNAPSHOT[0x90..0xb0], sha, "segments SHA256");
+
+    // M0 config, 0xb0..0xc8: enabled, entry and image offset of the segment
+    assert_eq!(word(0xb0) & 1, 1, "config_enable");
+    assert_eq!(word(0xbc), SEGMENTS_OFFSET as u32, "image_offset");
+    assert_eq!(word(0xc0), M0_LOAD_ADDR, "boot_entry");
+    // D0 and LP config, 0xc8..0xf8: not configured
+    assert!(SNAPSHOT[0xc8..0xf8].iter().all(|&b| b == 0));
+
+    assert_eq!(
+        word(0x15c),
+        CRC32.checksum(&SNAPSHOT[..0x15c]),
+        "header CRC"
+    );
+}
+
+#[test]
+fn snapshot_parses() {
+    let h = BootHeader::parse(SNAPSHOT).unwrap();
+    assert!(h.crc_ok());
+    assert_eq!(h.segment_count().unwrap(), 1);
+    assert_eq!(h.entry_points(), [("M0", M0_LOAD_ADDR)]);
+    assert_eq!(h.image_offsets(), [("M0", SEGMENTS_OFFSET as u32)]);
+    assert_eq!(h.segments_sha256(), SNAPSHOT[0x90..0xb0]);
+}
+
+#[test]
+fn build_reproduces_snapshot() {
+    let m0 = m0_data();
+    let seg = Segment::new(M0_LOAD_ADDR, &m0);
+    let ignore = IgnoreChecks::default();
+    let image = build_image(Some(seg), None, None, None, ignore, Some(FLASH_ID)).unwrap();
+    assert_eq!(image[..BOOT_HEADER_SIZE], SNAPSHOT[..]);
+
+    // The header's hash covers the segment header and data as written.
+    let end = SEGMENTS_OFFSET + SEGMENT_HEADER_SIZE + m0.len();
+    let sha: [u8; 32] = Sha256::digest(&image[SEGMENTS_OFFSET..end]).into();
+    assert_eq!(sha, SNAPSHOT[0x90..0xb0]);
+}
@@ -0,0 +1,96 @@
+// Build images with `build_image` and check that they parse back to what went
+// in, for one core and for several.
+use bl_boot::boot::{
+    build_image, check_round_trip, parse_image, IgnoreChecks, Image, Segment, BOOT_HEADER_SIZE,
+    BOOT_MAGIC, D0_LOAD_ADDR, IMAGE_ALIGNMENT, IMAGE_SEGMENT_ALIGNMENT, LP_LOAD_ADDR, M0_LOAD_ADDR,
+    SEGMENTS_OFFSET, SEGMENT_HEADER_SIZE,
+};
+
+// Winbond W25Q128, known to `FlashConfig::for_jedec`
+const FLASH_ID: [u8; 3] = [0xef, 0x40, 0x18];
+
+fn data(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+        .collect()
+}
+
+fn build(segments: [Option<Segment>; 3]) -> Vec<u8> {
+    let [m0, d0, lp] = segments;
+    let ignore = IgnoreChecks::default();
+    build_image(m0, d0, lp, None, ignore, Some(FLASH_ID)).unwrap()
+}
+
+// Check the header and each segment against what the image was built from.
+fn check(image: &[u8], cores: &[(&str, Segment)]) {
+    assert!(image.starts_with(BOOT_MAGIC));
+    assert_eq!(image[4..8], 1u32.to_le_bytes(), "header revision");
+    assert!(image.len().is_multiple_of(IMAGE_ALIGNMENT));
+    assert!(image[BOOT_HEADER_SIZE..SEGMENTS_OFFSET]
+        .iter()
+        .all(|&b| b == 0xff));
+
+    let i = Image::parse(image).unwrap();
+    assert!(i.header.crc_ok());
+    assert_eq!(i.header.segment_count().unwrap(), cores.len());
+    assert_eq!(i.segments.len(), cores.len());
+    assert_eq!(i.segments_sha256(), i.header.segments_sha256());
+
+    let mut offset = SEGMENTS_OFFSET;
+    let entries = i.header.entry_points();
+    let offsets = i.header.image_offsets();
+    for (n, (core, want)) in cores.iter().enumerate() {
+        let got = &i.segments[n];
+        let size = want.data.len().next_multiple_of(IMAGE_SEGMENT_ALIGNMENT);
+        let (a, s) = (got.header.address, got.header.size);
+        let wa = want.header.address;
+        assert_eq!(a, wa, "segment {n} address");
+        assert_eq!(s as usize, size, "segment {n} size");
+        assert!(got.header.crc_ok());
+        assert!(got.data.starts_with(want.data));
+        assert!(got.data[want.data.len()..].iter().all(|&b| b == 0));
+        assert_eq!(entries[n], (*core, a));
+        assert_eq!(offsets[n], (*core, offset as u32));
+        offset += SEGMENT_HEADER_SIZE + size;
+    }
+
+    let segments: Vec<Segment> = cores.iter().map(|(_, s)| *s).collect();
+    check_round_trip(image, &segments).unwrap();
+    let info = parse_image(image, true).unwrap();
+    assert_eq!(info.len(), cores.len());
+}
+
+#[test]
+fn single_core() {
+    let m0 = data(0x1234, 0x5a);
+    let s = Segment::new(M0_LOAD_ADDR, &m0);
+    let image = build([Some(s), None, None]);
+    check(&image, &[("M0", s)]);
+}
+
+#[test]
+fn d0_only() {
+    let d0 = data(0x800, 0x11);
+    let s = Segment::new(D0_LOAD_ADDR, &d0);
+    let image = build([None, Some(s), None]);
+    check(&image, &[("D0", s)]);
+}
+
+#[test]
+fn multi_core() {
+    let (m0, d0, lp) = (data(0x1001, 1), data(0x3000, 2), data(0x17, 3));
+    let m0 = Segment::new(M0_LOAD_ADDR, &m0);
+    let d0 = Segment::new(D0_LOAD_ADDR, &d0);
+    let lp = Segment::new(LP_LOAD_ADDR, &lp);
+    let image = build([Some(m0), Some(d0), Some(lp)]);
+    check(&image, &[("M0", m0), ("D0", d0), ("LP", lp)]);
+}
+
+#[test]
+fn corrupted_header_fails_crc() {
+    let m0 = data(0x100, 0);
+    let mut image = build([Some(Segment::new(M0_LOAD_ADDR, &m0)), None, None]);
+    image[0x90] ^= 1;
+    assert!(!Image::parse(&image).unwrap().header.crc_ok());
+    assert!(parse_image(&image, true).is_err());
+}
@@ -0,0 +1,36 @@
+// Format every `repr(C, packed)` struct with a `Display` impl on a zeroed
+// instance. Nested private structs, e.g. the boot header's flash and clock
+// config, are covered through their parents. Run under Miri to also catch
+// unaligned reads that rustc cannot see, e.g. through raw pointers.
+use bl_boot::boot::BootHeader;
+use bl_boot::efuses::{EfuseBlock0, EfuseBlock1};
+use bl_boot::partition::PartitionEntry;
+use bl_boot::protocol::BootInfo;
+use zerocopy::FromZeros;
+
+fn display<T: FromZeros + std::fmt::Display>() -> String {
+    T::new_zeroed().to_string()
+}
+
+#[test]
+fn boot_header() {
+    let s = display::<BootHeader>();
+    assert!(s.starts_with("Revision: 0\n"));
+    assert!(s.contains("LP config:"));
+}
+
+#[test]
+fn efuse_blocks() {
+    assert!(display::<EfuseBlock0>().contains("Password 1: 0000000000000000"));
+    assert!(display::<EfuseBlock1>().contains("Key 10: [00,"));
+}
+
+#[test]
+fn partition_entry() {
+    assert!(display::<PartitionEntry>().contains("type  0, active 0"));
+}
+
+#[test]
+fn boot_info() {
+    assert!(display::<BootInfo>().contains("Wi-Fi MAC: 00:00:00:00:00:00"));
+}